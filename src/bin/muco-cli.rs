@@ -1,11 +1,20 @@
 /// Muco Cli frontend
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 
+use muco::config::{DeviceEntry, GainMode, Gapless};
+use muco::doctor::Status;
 use muco::error::{MucoErrorKind as Kind, MucoResult as Result};
-use muco::herr;
+use muco::media::Container;
+use muco::playlist::{Entry, PlaylistStyle};
+use muco::preset::Preset;
+use muco::progress::{Bar, Events, Lines, Progress};
+use muco::{herr, herr_exit};
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use env_logger;
 use failure::ResultExt;
 
 fn main() {
@@ -37,6 +46,41 @@ fn main() {
                                 .required(true)
                                 .takes_value(true),
                         ),
+                )
+                .subcommand(
+                    SubCommand::with_name("scan")
+                        .about("Update the index of the libraries")
+                        .arg(
+                            Arg::with_name("name")
+                                .short("n")
+                                .required(false)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("full")
+                                .long("full")
+                                .help("Read every file again, even those which look unchanged"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("analyze")
+                        .about("Measure the loudness of the tracks not measured yet")
+                        .arg(
+                            Arg::with_name("name")
+                                .short("n")
+                                .required(false)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("dupes")
+                        .about("List the tracks found more than once in the libraries")
+                        .arg(
+                            Arg::with_name("name")
+                                .short("n")
+                                .required(false)
+                                .takes_value(true),
+                        ),
                 ),
         )
         .subcommand(
@@ -55,12 +99,24 @@ fn main() {
                         .required(false)
                         .multiple(false)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Print progress as text or as one JSON object per event")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("device")
                 .about("Manage devices")
                 .subcommand(SubCommand::with_name("list").alias("show"))
+                .subcommand(
+                    SubCommand::with_name("presets").about("List the presets devices can be added with"),
+                )
                 .subcommand(
                     SubCommand::with_name("add")
                         .arg(
@@ -82,14 +138,159 @@ fn main() {
                                 .short("f")
                                 .long("format")
                                 .takes_value(true)
-                                .required(true),
+                                .required_unless("preset"),
+                        )
+                        .arg(
+                            Arg::with_name("preset")
+                                .long("preset")
+                                .help("Start from the settings of a preset, see `muco device presets`")
+                                .takes_value(true)
+                                .required(false),
                         )
                         .arg(
                             Arg::with_name("exclude")
                                 .short("e")
                                 .long("exclude")
+                                .help("Regular expression of the library relative paths not to sync")
+                                .takes_value(true)
+                                .multiple(true)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("playlist")
+                                .short("p")
+                                .long("playlist")
+                                .help("Only sync the files of this playlist, may be repeated")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("query")
+                                .short("q")
+                                .long("query")
+                                .help("Only sync the files matching this query")
+                                .takes_value(true)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("library")
+                                .long("library")
+                                .help("Sync a library to a directory of the device, as NAME=DIR. May be repeated, other libraries are then left out")
+                                .takes_value(true)
+                                .validator(|v| {
+                                    if v.contains('=') {
+                                        Ok(())
+                                    } else {
+                                        Err("expected NAME=DIR".to_owned())
+                                    }
+                                })
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("companion")
+                                .long("companion")
+                                .help("Extension of the files to sync along with the track of the same name, e.g. lrc. May be repeated")
                                 .takes_value(true)
                                 .multiple(true)
+                                .number_of_values(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("gain")
+                                .long("gain")
+                                .help("Bring analyzed tracks to the same loudness with ReplayGain tags, or by changing the audio")
+                                .takes_value(true)
+                                .possible_values(&["none", "tags", "apply"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("gapless")
+                                .long("gapless")
+                                .help("Write the LAME header players need to play encoded MP3s without gaps, or leave it out")
+                                .takes_value(true)
+                                .possible_values(&["lame", "none"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("max-sample-rate")
+                                .long("max-sample-rate")
+                                .help("Highest sample rate the device plays, in Hz")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("max-bit-depth")
+                                .long("max-bit-depth")
+                                .help("Highest bit depth the device plays")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("max-channels")
+                                .long("max-channels")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("mono")
+                                .long("mono")
+                                .help("Downmix everything to mono, for single speaker devices"),
+                        )
+                        .arg(
+                            Arg::with_name("max-folders")
+                                .long("max-folders")
+                                .help("Folders the player reads, deeper folders are flattened when there are more")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("max-files")
+                                .long("max-files")
+                                .help("Files per folder the player reads, folders with more are split")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("max-depth")
+                                .long("max-depth")
+                                .help("Levels of folders the player reads, deeper ones are merged into their parent")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("sorted")
+                                .long("sorted")
+                                .help("Write files in name order, for players listing them in the order they were written"),
+                        )
+                        .arg(
+                            Arg::with_name("fat-names")
+                                .long("fat-names")
+                                .help("Replace the characters FAT file systems do not allow in names"),
+                        )
+                        .arg(
+                            Arg::with_name("bitrate")
+                                .long("bitrate")
+                                .help("Constant bitrate of MP3s in kbit/s, 320 by default")
+                                .takes_value(true)
+                                .validator(number)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("quality")
+                                .long("quality")
+                                .help("Variable bitrate quality of MP3s, from 0 the best to 9")
+                                .takes_value(true)
+                                .possible_values(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])
                                 .required(false),
                         ),
                 )
@@ -110,6 +311,130 @@ fn main() {
                 ),
         )
         .subcommand(SubCommand::with_name("sync").about("Synchronize managed devices & libraries"))
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage the transcode cache")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("stats").about("Show cache usage"))
+                .subcommand(SubCommand::with_name("prune").about("Evict down to the size limit"))
+                .subcommand(SubCommand::with_name("clear").about("Remove every cached file")),
+        )
+        .subcommand(
+            SubCommand::with_name("clone")
+                .about("Copy a device to another, without the libraries")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Print progress as text or as one JSON object per event")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("List the files found only on a device, or copy them into a library")
+                .arg(Arg::with_name("device").required(true))
+                .arg(
+                    Arg::with_name("library")
+                        .short("l")
+                        .help("Library to copy the files into")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .requires("library")
+                        .help("Remove the files from the device once imported"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that the files on a device are intact")
+                .arg(Arg::with_name("device").required(true))
+                .arg(
+                    Arg::with_name("quick")
+                        .long("quick")
+                        .help("Only compare against what was recorded when syncing, no decoding"),
+                )
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Sync the damaged files again from the libraries"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Print progress as text or as one JSON object per event")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check that tools, encoders, libraries and devices are ready to sync"),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("List the library files matching a query")
+                .arg(
+                    Arg::with_name("query")
+                        .help("e.g. 'genre:jazz AND year>=1960 AND NOT artist:\"Kenny G\"'")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("library")
+                        .short("l")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("m3u")
+                        .long("m3u")
+                        .help("Print an M3U playlist instead of paths"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Synchronize devices automatically when they get mounted")
+                .arg(
+                    Arg::with_name("mountinfo")
+                        .short("m")
+                        .long("mountinfo")
+                        .takes_value(true)
+                        .default_value(muco::watch::MOUNTINFO),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .help("Seconds between polls of the mount table")
+                        .takes_value(true)
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::with_name("libraries")
+                        .short("L")
+                        .long("libraries")
+                        .help("Watch libraries and transcode new files ahead of time"),
+                ),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -120,7 +445,29 @@ fn main() {
             herr!(handle_library(library));
         }
         ("sync", Some(sync)) => {
-            herr!(handle_sync(sync));
+            let res = handle_sync(sync);
+            herr_exit!(res, 1);
+        }
+        ("cache", Some(cache)) => {
+            herr!(handle_cache(cache));
+        }
+        ("clone", Some(clone)) => {
+            herr!(handle_clone(clone));
+        }
+        ("import", Some(import)) => {
+            herr!(handle_import(import));
+        }
+        ("verify", Some(verify)) => {
+            herr!(handle_verify(verify));
+        }
+        ("doctor", Some(_)) => {
+            herr!(handle_doctor());
+        }
+        ("query", Some(query)) => {
+            herr!(handle_query(query));
+        }
+        ("watch", Some(watch)) => {
+            herr!(handle_watch(watch));
         }
         _ => (),
     }
@@ -130,15 +477,114 @@ fn handle_device(dev: &ArgMatches) -> Result<()> {
     match dev.subcommand() {
         ("add", Some(m)) => {
             // Can unwrap here safely because of clap configuration
-            let fmt = m.value_of("format").unwrap().to_owned();
-            let nam = m.value_of("name").unwrap().to_owned();
-
-            let loc = PathBuf::from(m.value_of("location").unwrap_or(env!("PWD")));
-            let excl = m.value_of("exclude").map(|excl| excl.to_owned());
+            let name = m.value_of("name").unwrap().to_owned();
+            let location = PathBuf::from(m.value_of("location").unwrap_or(env!("PWD")));
 
             let conf = muco::config::Config::get()?;
-            muco::device::add(conf, nam, loc, excl, fmt)?;
+            // A preset gives the defaults, which the other options override
+            let mut entry = match m.value_of("preset") {
+                Some(preset) => Preset::get(&conf, preset)?.entry(name, location),
+                None => DeviceEntry {
+                    name,
+                    location,
+                    excludes: None,
+                    format: Container::try_from(m.value_of("format").unwrap())?,
+                    only_playlists: Vec::new(),
+                    query: None,
+                    companions: Vec::new(),
+                    gapless: Default::default(),
+                    playlists: Default::default(),
+                    gain: Default::default(),
+                    capabilities: Default::default(),
+                    encoder: Default::default(),
+                    layout: Default::default(),
+                    art: Default::default(),
+                    libraries: BTreeMap::new(),
+                },
+            };
+
+            if let Some(format) = m.value_of("format") {
+                entry.format = Container::try_from(format)?;
+            }
+            if let Some(excludes) = m.value_of("exclude") {
+                entry.excludes = Some(excludes.to_owned());
+            }
+            if let Some(playlists) = m.values_of("playlist") {
+                entry.only_playlists = playlists.map(PathBuf::from).collect();
+            }
+            if let Some(query) = m.value_of("query") {
+                entry.query = Some(query.to_owned());
+            }
+            if let Some(companions) = m.values_of("companion") {
+                entry.companions = companions.map(|c| c.to_owned()).collect();
+            }
+
+            for mapping in m.values_of("library").into_iter().flatten() {
+                if let Some((library, dir)) = mapping.split_once('=') {
+                    entry
+                        .libraries
+                        .insert(library.to_owned(), PathBuf::from(dir));
+                }
+            }
+
+            match m.value_of("gapless") {
+                Some("none") => entry.gapless = Gapless::None,
+                Some(_) => entry.gapless = Gapless::Lame,
+                None => (),
+            }
+
+            match m.value_of("gain") {
+                Some("tags") => entry.gain.mode = GainMode::Tags,
+                Some("apply") => entry.gain.mode = GainMode::Apply,
+                Some(_) => entry.gain.mode = GainMode::None,
+                None => (),
+            }
 
+            let caps = &mut entry.capabilities;
+            if let Some(rate) = m.value_of("max-sample-rate") {
+                caps.max_sample_rate = rate.parse().ok();
+            }
+            if let Some(bits) = m.value_of("max-bit-depth") {
+                caps.max_bit_depth = bits.parse().ok();
+            }
+            if let Some(channels) = m.value_of("max-channels") {
+                caps.max_channels = channels.parse().ok();
+            }
+            caps.mono |= m.is_present("mono");
+
+            let layout = &mut entry.layout;
+            if let Some(folders) = m.value_of("max-folders") {
+                layout.max_folders = folders.parse().ok();
+            }
+            if let Some(files) = m.value_of("max-files") {
+                layout.max_files = files.parse().ok();
+            }
+            if let Some(depth) = m.value_of("max-depth") {
+                layout.max_depth = depth.parse().ok();
+            }
+            layout.sorted |= m.is_present("sorted");
+            layout.fat_names |= m.is_present("fat-names");
+
+            if let Some(bitrate) = m.value_of("bitrate") {
+                entry.encoder.bitrate = bitrate.parse().ok();
+            }
+            if let Some(quality) = m.value_of("quality") {
+                entry.encoder.quality = quality.parse().ok();
+            }
+
+            muco::device::add(conf, entry)?;
+
+            Ok(())
+        }
+        ("presets", Some(_)) => {
+            let conf = muco::config::Config::get()?;
+            for (name, preset) in Preset::all(&conf)? {
+                println!(
+                    "{:<24}{}",
+                    name,
+                    preset.description.as_deref().unwrap_or_default()
+                );
+            }
             Ok(())
         }
         //TODO: Add device list/status
@@ -157,6 +603,48 @@ fn handle_library(lib: &ArgMatches) -> Result<()> {
             let conf = muco::config::Config::get()?;
             muco::library::add(conf, nam, loc)
         }
+        ("scan", Some(m)) => {
+            let nam = m.value_of("name").map(|n| n.to_owned());
+
+            let conf = muco::config::Config::get()?;
+            for (library, changes) in
+                muco::library::Library::scan(&conf, nam, m.is_present("full"))?
+            {
+                println!(
+                    "{}: {} files, {} added, {} changed, {} removed",
+                    library.name(),
+                    library.files().len(),
+                    changes.added,
+                    changes.changed,
+                    changes.removed
+                );
+            }
+            Ok(())
+        }
+        ("analyze", Some(m)) => {
+            let nam = m.value_of("name").map(|n| n.to_owned());
+
+            let conf = muco::config::Config::get()?;
+            muco::media::require_ffmpeg("measure loudness")?;
+            for mut library in muco::library::Library::get(&conf, nam)? {
+                let measured = library.analyze(&mut |path| println!("{}", path.display()));
+                println!("{}: {} files measured", library.name(), measured);
+            }
+            Ok(())
+        }
+        ("dupes", Some(m)) => {
+            let nam = m.value_of("name").map(|n| n.to_owned());
+
+            let conf = muco::config::Config::get()?;
+            let mut libraries = muco::library::Library::get(&conf, nam)?;
+            for group in muco::library::duplicates(&mut libraries) {
+                println!("{}", group[0].display());
+                for copy in group[1..].iter() {
+                    println!("  {}", copy.display());
+                }
+            }
+            Ok(())
+        }
         //TODO: Add library list/status
         _ => unimplemented!(),
     }
@@ -176,6 +664,234 @@ fn handle_sync(dev: &ArgMatches) -> Result<()> {
     // dbg!(&devices);
     // dbg!(&library);
 
-    muco::sync(&conf, devices, library).context(Kind::Unknown)?;
+    let mut progress = progress(dev);
+    muco::sync(&conf, devices, library, progress.as_mut()).context(Kind::Unknown)?;
+    Ok(())
+}
+
+fn handle_clone(m: &ArgMatches) -> Result<()> {
+    // Can unwrap here safely because of clap configuration
+    let from = m.value_of("from").unwrap().to_owned();
+    let to = m.value_of("to").unwrap().to_owned();
+
+    let conf = muco::config::Config::get()?.get_online()?;
+    let from = muco::device::Device::get(&conf, Some(from))?.remove(0);
+    let to = muco::device::Device::get(&conf, Some(to))?.remove(0);
+
+    let mut progress = progress(m);
+    muco::clone_device(&conf, &from, to, progress.as_mut())
+}
+
+fn number(value: String) -> std::result::Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+// Progress as asked for by --output, a bar on terminals
+fn progress(m: &ArgMatches) -> Box<dyn Progress> {
+    if m.value_of("output") == Some("json") {
+        Box::new(Events::new(|event| {
+            if let Ok(line) = serde_json::to_string(&event) {
+                println!("{}", line);
+            }
+        }))
+    } else if std::io::stderr().is_terminal() {
+        Box::new(Bar::default())
+    } else {
+        Box::new(Lines::default())
+    }
+}
+
+fn handle_cache(m: &ArgMatches) -> Result<()> {
+    let conf = muco::config::Config::get()?;
+    let cache = muco::cache::Cache::open(conf.get_cache())?;
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+    match m.subcommand_name() {
+        Some("stats") => {
+            let stats = cache.stats()?;
+            println!("Entries: {}", stats.entries);
+            println!(
+                "Size: {:.1} MiB of {:.1} MiB",
+                mib(stats.size),
+                mib(cache.max_size())
+            );
+        }
+        Some("prune") => {
+            let evicted = cache.prune(&[])?;
+            println!(
+                "Evicted {} files, {:.1} MiB",
+                evicted.entries,
+                mib(evicted.size)
+            );
+        }
+        Some("clear") => {
+            let removed = cache.clear()?;
+            println!(
+                "Removed {} files, {:.1} MiB",
+                removed.entries,
+                mib(removed.size)
+            );
+        }
+        _ => unreachable!(),
+    }
     Ok(())
 }
+
+fn handle_import(m: &ArgMatches) -> Result<()> {
+    // Can unwrap here safely because of clap configuration
+    let device = m.value_of("device").unwrap().to_owned();
+
+    let conf = muco::config::Config::get()?.get_online()?;
+    let libraries = muco::library::Library::get(&conf, None)?;
+    let devices = muco::device::Device::get(&conf, Some(device))?;
+    let foreign = muco::import::foreign(&devices[0], &libraries);
+
+    match m.value_of("library") {
+        Some(name) => {
+            let library = libraries
+                .iter()
+                .find(|l| l.name() == name)
+                .ok_or(Kind::Nonexistant)?;
+            let imported =
+                muco::import::import(&devices[0], &foreign, library, m.is_present("remove"))?;
+            for path in imported.iter() {
+                println!("Imported {}", path.display());
+            }
+            println!("{} files imported into {}", imported.len(), library.name());
+        }
+        None => {
+            for path in foreign.iter() {
+                println!("{}", path.display());
+            }
+            println!("{} files not from any library", foreign.len());
+        }
+    }
+    Ok(())
+}
+
+fn handle_verify(m: &ArgMatches) -> Result<()> {
+    // Can unwrap here safely because of clap configuration
+    let device = m.value_of("device").unwrap().to_owned();
+
+    let conf = muco::config::Config::get()?.get_online()?;
+    let dev = muco::device::Device::get(&conf, Some(device))?.remove(0);
+
+    let terminal = std::io::stderr().is_terminal();
+    let damaged = muco::verify::verify(&dev, !m.is_present("quick"), &mut |path| {
+        if terminal {
+            eprint!("\r\x1b[KChecking {}", path.display());
+        }
+    })?;
+    if terminal {
+        eprint!("\r\x1b[K");
+    }
+    for (path, problem) in damaged.iter() {
+        println!("{}: {}", path.display(), problem);
+    }
+    println!("{} damaged files on {}", damaged.len(), dev.name());
+
+    if damaged.is_empty() {
+        return Ok(());
+    }
+    if !m.is_present("fix") {
+        Err(Kind::Verify)?
+    }
+
+    let library = muco::library::Library::get(&conf, None)?;
+    let files: Vec<_> = damaged.into_iter().map(|(path, _)| path).collect();
+    let mut progress = progress(m);
+    let orphans = muco::resync(&conf, dev, library, &files, progress.as_mut())?;
+    for path in orphans.iter() {
+        println!("No library has {}, not fixed", path.display());
+    }
+    if orphans.is_empty() {
+        Ok(())
+    } else {
+        Err(Kind::Verify)?
+    }
+}
+
+fn handle_doctor() -> Result<()> {
+    let mut failed = 0;
+    for (section, checks) in muco::doctor::run() {
+        if checks.is_empty() {
+            continue;
+        }
+        println!("{}", section);
+        for check in checks {
+            println!("  {:<8} {}: {}", check.status, check.what, check.detail);
+            if check.status == Status::Failed {
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(Kind::Doctor)?
+    }
+    Ok(())
+}
+
+fn handle_query(m: &ArgMatches) -> Result<()> {
+    // Can unwrap here safely because of clap configuration
+    let query = muco::query::Query::try_from(m.value_of("query").unwrap())?;
+    let library_to_query = m.value_of("library").map(|s| s.to_owned());
+
+    let conf = muco::config::Config::get()?;
+    let mut found = Vec::new();
+    for library in muco::library::Library::get(&conf, library_to_query)? {
+        found.extend(library.query(&query));
+    }
+
+    if !m.is_present("m3u") {
+        for (path, _) in found {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    let entries: Vec<_> = found
+        .iter()
+        .map(|(path, meta)| Entry {
+            path: path.clone(),
+            title: match (meta.get("artist"), meta.get("title")) {
+                (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+                (None, Some(title)) => Some(title.to_owned()),
+                _ => None,
+            },
+            duration: meta.duration.map_or(-1, |d| d.round() as i64),
+        })
+        .collect();
+    let style = PlaylistStyle {
+        absolute: true,
+        ..PlaylistStyle::default()
+    };
+    let root = PathBuf::from("/");
+    let entries: Vec<_> = entries.iter().map(|e| (e, e.path.clone())).collect();
+
+    std::io::stdout()
+        .write_all(&muco::playlist::render(&entries, &root, &root, &style))
+        .context(Kind::Playlist)?;
+    Ok(())
+}
+
+fn handle_watch(watch: &ArgMatches) -> Result<()> {
+    // Can unwrap here safely because of the default values
+    let mountinfo = PathBuf::from(watch.value_of("mountinfo").unwrap());
+    let interval = watch
+        .value_of("interval")
+        .unwrap()
+        .parse::<u64>()
+        .context(Kind::Unknown)?;
+
+    let mut watcher = muco::watch::Watcher::new(mountinfo, Duration::from_secs(interval));
+    if watch.is_present("libraries") {
+        let conf = muco::config::Config::get()?;
+        watcher = watcher.watch_libraries(&conf)?;
+    }
+
+    watcher.run()
+}
@@ -0,0 +1,290 @@
+/// Content addressed cache of transcoded files
+///
+/// Transcodes are keyed by a hash of the source file, its path, size and
+/// modification time, and of the encoder profile, so devices wanting the
+/// same output share a single transcode without reading the source again.
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::ResultExt;
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::config::CacheConf;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::media::{self, Profile};
+use crate::utils::hex;
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    root: PathBuf,
+    // In bytes
+    max_size: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub entries: usize,
+    pub size: u64,
+}
+
+impl Cache {
+    /// Opens the cache under `$XDG_CACHE_HOME/muco`, creating it if needed
+    pub fn open(conf: &CacheConf) -> Result<Cache> {
+        let root = xdg::BaseDirectories::with_prefix("muco")
+            .context(Kind::Cache)?
+            .create_cache_directory("transcodes")
+            .context(Kind::Cache)?;
+
+        Ok(Cache::new(root, conf.max_size * 1024 * 1024))
+    }
+
+    fn new(root: PathBuf, max_size: u64) -> Cache {
+        Cache { root, max_size }
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Where the transcode of `source` with `profile` is kept
+    pub fn path(&self, source: &Path, profile: &Profile) -> Result<PathBuf> {
+        let meta = source.metadata().context(Kind::Cache)?;
+        let mtime = meta
+            .modified()
+            .context(Kind::Cache)?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.input(source.as_os_str().as_encoded_bytes());
+        hasher.input(meta.len().to_le_bytes());
+        hasher.input(mtime.as_nanos().to_le_bytes());
+        let source_hash = hex(&hasher.result());
+        let profile_hash = hex(&Sha256::digest(profile.id().as_bytes()));
+
+        let extension: &'static str = profile.format().into();
+        Ok(self
+            .root
+            .join(&source_hash[..2])
+            .join(format!("{}-{}", source_hash, &profile_hash[..16]))
+            .with_extension(extension))
+    }
+
+    /// Returns the cached transcode, marking it as recently used
+    pub fn get(&self, source: &Path, profile: &Profile) -> Result<Option<PathBuf>> {
+        let cached = self.path(source, profile)?;
+
+        if cached.is_file() {
+            debug!("Cache hit for {}: {}", source.display(), cached.display());
+            touch(&cached);
+            Ok(Some(cached))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the cached transcode, transcoding `source` into the cache first
+    /// when needed. The cache may grow beyond its size limit until pruned.
+    pub fn get_or_insert(
+        &self,
+        source: &Path,
+        profile: &Profile,
+        progress: &mut dyn FnMut(f64),
+    ) -> Result<PathBuf> {
+        let cached = self.path(source, profile)?;
+
+        if cached.is_file() {
+            debug!("Cache hit for {}: {}", source.display(), cached.display());
+            touch(&cached);
+            return Ok(cached);
+        }
+
+        if let Some(parent) = cached.parent() {
+            std::fs::create_dir_all(parent).context(Kind::Cache)?;
+        }
+
+        // ffmpeg picks the muxer from the extension, keep it on the partial file
+        let extension: &'static str = profile.format().into();
+        let partial = cached.with_extension(format!("part.{}", extension));
+
+        debug!("Caching {} as {}", source.display(), cached.display());
+        if let Err(err) = media::transcode(source, &partial, profile, progress) {
+            let _ = std::fs::remove_file(&partial);
+            return Err(err);
+        }
+        std::fs::rename(&partial, &cached).context(Kind::Cache)?;
+        Ok(cached)
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self
+            .entries()?
+            .iter()
+            .fold(Stats::default(), |stats, (_, size, _)| Stats {
+                entries: stats.entries + 1,
+                size: stats.size + size,
+            }))
+    }
+
+    /// Evicts the least recently used transcodes until the cache fits its
+    /// size limit, never those in `keep`. Returns what was evicted.
+    pub fn prune(&self, keep: &[PathBuf]) -> Result<Stats> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        let mut evicted = Stats::default();
+
+        entries.sort_by_key(|(_, _, used)| *used);
+        for (path, len, _) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if keep.contains(&path) {
+                continue;
+            }
+
+            info!("Evicting {} from the cache", path.display());
+            std::fs::remove_file(&path).context(Kind::Cache)?;
+            size -= len;
+            evicted.entries += 1;
+            evicted.size += len;
+        }
+
+        Ok(evicted)
+    }
+
+    /// Removes every transcode. Returns what was removed.
+    pub fn clear(&self) -> Result<Stats> {
+        let stats = self.stats()?;
+        std::fs::remove_dir_all(&self.root).context(Kind::Cache)?;
+        std::fs::create_dir_all(&self.root).context(Kind::Cache)?;
+        Ok(stats)
+    }
+
+    // Cached files along with their size and last use
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry.context(Kind::Cache)?;
+            if entry.file_type().is_file() {
+                let meta = entry.metadata().context(Kind::Cache)?;
+                let used = meta.modified().context(Kind::Cache)?;
+                entries.push((entry.into_path(), meta.len(), used));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+// Access times are unreliable with relatime/noatime, the modification time
+// of a cached file records its last use instead.
+fn touch(path: &Path) {
+    if let Ok(file) = File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Encoder;
+    use crate::media::Container;
+    use std::time::Duration;
+
+    // A cache and a directory for sources, both below `root`
+    fn cache(root: &Path, max_size: u64) -> (Cache, PathBuf) {
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root.join("cache")).unwrap();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        (Cache::new(root.join("cache"), max_size), root.join("lib"))
+    }
+
+    fn mp3() -> Profile {
+        Profile::new(Container::Mp3, &Encoder::default()).unwrap()
+    }
+
+    // Puts `content` in the cache as the transcode of `source`, last used
+    // `age` seconds ago
+    fn insert(cache: &Cache, source: &Path, content: &str, age: u64) -> PathBuf {
+        let cached = cache.path(source, &mp3()).unwrap();
+        std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        std::fs::write(&cached, content).unwrap();
+        let file = File::options().append(true).open(&cached).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+        cached
+    }
+
+    #[test]
+    fn transcodes_are_found_until_their_source_changes() {
+        let root = std::env::temp_dir().join(format!("muco-cache-hit-{}", std::process::id()));
+        let (cache, lib) = cache(&root, 1024);
+        let source = lib.join("a.flac");
+        std::fs::write(&source, "flac").unwrap();
+        let flac = Profile::new(Container::Flac, &Encoder::default()).unwrap();
+
+        assert_eq!(cache.get(&source, &mp3()).unwrap(), None);
+        let cached = insert(&cache, &source, "mp3", 0);
+        assert_eq!(cache.get(&source, &mp3()).unwrap(), Some(cached));
+        assert_eq!(cache.get(&source, &flac).unwrap(), None);
+
+        std::fs::write(&source, "new flac").unwrap();
+        assert_eq!(cache.get(&source, &mp3()).unwrap(), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_transcodes_are_evicted_first() {
+        let root = std::env::temp_dir().join(format!("muco-cache-lru-{}", std::process::id()));
+        let (cache, lib) = cache(&root, 8);
+        let cached: Vec<PathBuf> = [("a", 30), ("b", 10), ("c", 20)]
+            .iter()
+            .map(|(name, age)| {
+                let source = lib.join(name);
+                std::fs::write(&source, name).unwrap();
+                insert(&cache, &source, "four", *age)
+            })
+            .collect();
+
+        let evicted = cache.prune(&[]).unwrap();
+        assert_eq!(
+            evicted,
+            Stats {
+                entries: 1,
+                size: 4
+            }
+        );
+        assert!(!cached[0].exists());
+        assert!(cached[1].exists() && cached[2].exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn kept_transcodes_are_not_evicted() {
+        let root = std::env::temp_dir().join(format!("muco-cache-keep-{}", std::process::id()));
+        let (cache, lib) = cache(&root, 0);
+        let source = lib.join("a.flac");
+        std::fs::write(&source, "flac").unwrap();
+        let cached = insert(&cache, &source, "mp3", 0);
+
+        let evicted = cache.prune(std::slice::from_ref(&cached)).unwrap();
+        assert_eq!(evicted, Stats::default());
+        assert!(cached.exists());
+        let evicted = cache.prune(&[]).unwrap();
+        assert_eq!(
+            evicted,
+            Stats {
+                entries: 1,
+                size: 3
+            }
+        );
+        assert!(!cached.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use failure::ResultExt;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use log::{error, info};
@@ -8,30 +9,292 @@ use toml;
 use crate::error::MucoErrorKind as Kind;
 use crate::error::MucoResult as Result;
 use crate::media::Container;
+use crate::playlist::PlaylistStyle;
+use crate::preset::Preset;
 use crate::utils::*;
 
 pub type LibraryConf = Vec<(String, PathBuf)>;
-pub type DeviceConf = Vec<(String, PathBuf, Option<String>, Container)>;
+pub type DeviceConf = Vec<DeviceEntry>;
+
+/// A configured device. Older configurations stored devices as
+/// `[name, location, excludes, format]` arrays, which still deserialize.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub name: String,
+    pub location: PathBuf,
+    /// Regular expression of the files not synced, matched anywhere in
+    /// their path relative to their library
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excludes: Option<String>,
+    pub format: Container,
+    /// Only sync the files these playlists refer to. Relative paths are
+    /// looked up in the libraries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only_playlists: Vec<PathBuf>,
+    /// Only sync the files matching this query, see [`crate::query`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Extensions of the files synced along with the track of the same
+    /// name, e.g. `lrc` lyrics, renamed after the track on the device
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub companions: Vec<String>,
+    #[serde(default)]
+    pub gapless: Gapless,
+    #[serde(default)]
+    pub playlists: PlaylistStyle,
+    #[serde(default)]
+    pub gain: GainConf,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    #[serde(default)]
+    pub encoder: Encoder,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub art: ArtConf,
+    /// Directory of the device each library goes to, e.g. `Podcasts =
+    /// "/Podcasts"`. Libraries left out are not synced. When empty, every
+    /// library goes to the root of the device, the first one configured
+    /// winning when two have a file at the same place.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub libraries: BTreeMap<String, PathBuf>,
+}
+
+/// What a device can play, set under `[devices.capabilities]`. Files going
+/// beyond are transcoded, even when already in the device format.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// In Hz, files above are resampled to the nearest rate below
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sample_rate: Option<u32>,
+    /// Only matters to lossless formats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bit_depth: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_channels: Option<u8>,
+    /// Single speaker devices, everything is downmixed to mono
+    pub mono: bool,
+}
+
+/// How files are encoded for a device, set under `[devices.encoder]`. Only
+/// lossy formats have a bitrate.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Encoder {
+    /// Constant bitrate in kbit/s, 320 when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    /// Variable bitrate quality, from 0 the best to 9, used over `bitrate`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+}
+
+/// Limits of players which only read part of a device, set under
+/// `[devices.layout]`, see [`crate::layout`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    /// Folders the player reads, deeper folders are flattened when there
+    /// are more
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_folders: Option<usize>,
+    /// Files per folder the player reads, folders with more are split
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+    /// Levels of folders the player reads, deeper ones are merged into
+    /// their parent at the last level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Names only use characters FAT file systems allow, others being
+    /// replaced by `_`
+    pub fat_names: bool,
+    /// Write files in name order, for players listing them in the order
+    /// they were written
+    pub sorted: bool,
+}
+
+/// Where the picture written next to the tracks of a folder comes from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sidecar {
+    /// No picture is written
+    None,
+    /// A picture found in the library folder of the tracks
+    Copy,
+    /// The art embedded in the first track which has some, else a picture
+    /// found in the library folder
+    Extract,
+}
+
+/// Album art on a device, set under `[devices.art]`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArtConf {
+    pub sidecar: Sidecar,
+    /// Name of the picture written next to the tracks
+    pub name: String,
+    /// Largest width and height of the picture in pixels, larger ones are
+    /// scaled down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u32>,
+    /// Remove the art embedded in the tracks, to save space or for players
+    /// choking on it
+    pub strip: bool,
+}
+
+impl Default for ArtConf {
+    fn default() -> Self {
+        ArtConf {
+            sidecar: Sidecar::None,
+            name: "folder.jpg".to_owned(),
+            max_size: None,
+            strip: false,
+        }
+    }
+}
+
+/// Whether encoded MP3s tell players how much silence the encoder added at
+/// either end, for albums to play without gaps between tracks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gapless {
+    /// Encoder delay and padding go in the LAME header of the first frame
+    #[default]
+    Lame,
+    /// No header frame, for players choking on it
+    None,
+}
+
+/// How tracks are brought to the same loudness on a device
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GainMode {
+    /// Tracks are synced as they are
+    None,
+    /// ReplayGain tags are written, for players which support them
+    Tags,
+    /// The gain is applied to the audio, for players which do not
+    Apply,
+}
+
+/// Set under `[devices.gain]`. Only tracks analyzed with `muco library
+/// analyze` are adjusted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GainConf {
+    pub mode: GainMode,
+    /// Loudness tracks are brought to, in LUFS
+    pub target: f64,
+    /// True peak not to go over when applying the gain, in dBTP
+    pub ceiling: f64,
+}
+
+impl Default for GainConf {
+    fn default() -> Self {
+        GainConf {
+            mode: GainMode::None,
+            // ReplayGain 2.0 reference level
+            target: -18.0,
+            ceiling: -1.0,
+        }
+    }
+}
+
+/// Commands executed by `muco watch` around an automatic sync
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Hooks {
+    pub on_start: Option<String>,
+    pub on_finish: Option<String>,
+    pub on_failure: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheConf {
+    /// Size limit of the transcode cache in MiB
+    pub max_size: u64,
+}
+
+impl Default for CacheConf {
+    fn default() -> Self {
+        CacheConf { max_size: 10240 }
+    }
+}
+
+/// Rules of a library, set under `[library.<name>]`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibraryRules {
+    /// When a track is there in several files, e.g. a FLAC and an MP3 with
+    /// the same path, only sync the one of the best quality
+    pub best_source: bool,
+    /// Containers from the best to the worst. Files in the same container
+    /// are ranked by bit depth, then bitrate, then sample rate.
+    pub prefer: Vec<Container>,
+}
+
+impl Default for LibraryRules {
+    fn default() -> Self {
+        LibraryRules {
+            best_source: false,
+            prefer: vec![Container::Flac, Container::Webm, Container::Mp3],
+        }
+    }
+}
+
+/// What to do with a track found more than once in the libraries
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Duplicates {
+    /// Sync every copy
+    All,
+    /// Only sync the first copy, libraries coming in the configured order
+    First,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncConf {
+    pub duplicates: Duplicates,
+}
+
+impl Default for SyncConf {
+    fn default() -> Self {
+        SyncConf {
+            duplicates: Duplicates::All,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     libraries: LibraryConf,
     devices: DeviceConf,
+    #[serde(default)]
+    hooks: Hooks,
+    #[serde(default)]
+    cache: CacheConf,
+    #[serde(default)]
+    sync: SyncConf,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    library: BTreeMap<String, LibraryRules>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    preset: BTreeMap<String, Preset>,
 }
 
 impl Config {
-    pub fn add_device(&mut self, n: String, l: PathBuf, e: Option<String>, f: Container) -> Result<()> {
-        if self.devices.iter().any(|(_nam, loc, _excl, _f)| loc.eq(&l)) {
-            error!("Device already configured at {}", l.display());
+    pub fn add_device(&mut self, d: DeviceEntry) -> Result<()> {
+        if self.devices.iter().any(|dev| dev.location.eq(&d.location)) {
+            error!("Device already configured at {}", d.location.display());
             Err(Kind::DuplicateDevice)?
-        } else if l.exists() {
-            print!("Adding device {} at {}...", &n, &l.display());
-            self.devices.push((n, l, e, f));
+        } else if d.location.exists() {
+            print!("Adding device {} at {}...", &d.name, &d.location.display());
+            self.devices.push(d);
             self.save().context(Kind::ConfigurationFile)?;
             println!(" Done");
             Ok(())
         } else {
-            error!("Bad path {}", l.display());
+            error!("Bad path {}", d.location.display());
             Err(Kind::Nonexistant)?
         }
     }
@@ -67,8 +330,9 @@ impl Config {
             devices: self
                 .devices
                 .into_iter()
-                .filter(|(_, path, _, _)| path.exists())
+                .filter(|d| d.location.exists())
                 .collect(),
+            ..self
         })
     }
 
@@ -80,6 +344,26 @@ impl Config {
         &self.devices
     }
 
+    pub fn get_library_rules(&self, name: &str) -> LibraryRules {
+        self.library.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn get_presets(&self) -> &BTreeMap<String, Preset> {
+        &self.preset
+    }
+
+    pub fn get_sync(&self) -> &SyncConf {
+        &self.sync
+    }
+
+    pub fn get_hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    pub fn get_cache(&self) -> &CacheConf {
+        &self.cache
+    }
+
     fn save(&self) -> Result<()> {
         let config = toml::to_string(&self).context(Kind::Serde)?;
         std::fs::write(get_config_file().context(Kind::ConfigurationFile)?, config)
@@ -88,10 +372,52 @@ impl Config {
     }
 }
 
-fn parse_conf(c: PathBuf) -> Result<Config> {
+pub(crate) fn parse_conf(c: PathBuf) -> Result<Config> {
     let conf_string = std::fs::read_to_string(&c).context(Kind::ConfigurationFile)?;
     let conf: Config = toml::from_str(&conf_string).context(Kind::Serde)?;
 
     info!("Configuration successfully read from {}", c.display());
     Ok(conf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_of_older_configurations_are_read() {
+        let conf: Config = toml::from_str(
+            r#"
+            libraries = [["Music", "/home/aj/Music"]]
+            devices = [["Car", "/run/media/aj/CAR", "Podcasts/.*", "Mp3"]]
+            "#,
+        )
+        .unwrap();
+
+        let car = &conf.get_devices()[0];
+        assert_eq!(car.name, "Car");
+        assert_eq!(car.location, PathBuf::from("/run/media/aj/CAR"));
+        assert_eq!(car.excludes.as_deref(), Some("Podcasts/.*"));
+        assert_eq!(car.format, Container::Mp3);
+        assert!(car.only_playlists.is_empty());
+        assert_eq!(car.gapless, Gapless::Lame);
+        assert_eq!(car.layout, Layout::default());
+    }
+
+    #[test]
+    fn devices_are_saved_as_tables() {
+        let conf: Config = toml::from_str(
+            r#"
+            libraries = []
+            devices = [["Car", "/run/media/aj/CAR", "Podcasts/.*", "Mp3"]]
+            "#,
+        )
+        .unwrap();
+
+        let saved = toml::to_string(&conf).unwrap();
+        assert!(saved.contains("[[devices]]"));
+        let read: Config = toml::from_str(&saved).unwrap();
+        assert_eq!(read.get_devices()[0].name, "Car");
+        assert_eq!(read.get_devices()[0].format, Container::Mp3);
+    }
+}
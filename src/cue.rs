@@ -0,0 +1,327 @@
+/// CUE sheets describing the tracks of a single file album
+///
+/// An album ripped as one file, `Album.flac` next to `Album.cue`, is synced
+/// as its tracks: each is cut out of the file with the tags of the sheet.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use log::{debug, error};
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::layout::fat_name;
+use crate::metadata::Metadata;
+
+// CUE times are in minutes, seconds and frames of 1/75 s
+const FRAMES: f64 = 75.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub number: u32,
+    /// In seconds from the start of the file, that of INDEX 01
+    pub start: f64,
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sheet {
+    /// The audio file the sheet describes
+    pub file: PathBuf,
+    /// Album tags, `album`, `albumartist`, `date` and `genre`
+    pub tags: BTreeMap<String, String>,
+    pub tracks: Vec<Track>,
+}
+
+pub fn is_cue(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("cue"))
+        .unwrap_or(false)
+}
+
+impl Sheet {
+    /// Reads a sheet, resolving its file against the sheet location. Only
+    /// sheets of a single file are supported.
+    pub fn read(path: &Path) -> Result<Sheet> {
+        let bytes = std::fs::read(path).context(Kind::Cue)?;
+        // Sheets written by older rippers are often Latin-1
+        let content = match String::from_utf8(bytes) {
+            Ok(content) => content,
+            Err(err) => err.into_bytes().iter().map(|b| *b as char).collect(),
+        };
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut files = Vec::new();
+        let mut tags = BTreeMap::new();
+        let mut tracks: Vec<Track> = Vec::new();
+
+        for line in content.lines() {
+            let words = split(line.trim().trim_start_matches('\u{feff}'));
+            let words: Vec<&str> = words.iter().map(String::as_str).collect();
+            let album = tracks.is_empty();
+            let tags = match tracks.last_mut() {
+                Some(track) => &mut track.tags,
+                None => &mut tags,
+            };
+
+            match words.as_slice() {
+                ["FILE", name, ..] => files.push(base.join(name)),
+                ["TRACK", number, ..] => tracks.push(Track {
+                    number: number.parse().unwrap_or(tracks.len() as u32 + 1),
+                    start: 0.0,
+                    tags: BTreeMap::new(),
+                }),
+                ["INDEX", "01", time] => match (tracks.last_mut(), parse_time(time)) {
+                    (Some(track), Some(start)) => track.start = start,
+                    _ => debug!("Bad index in {}: {}", path.display(), line),
+                },
+                ["TITLE", title] => {
+                    let key = if album { "album" } else { "title" };
+                    tags.insert(key.to_owned(), (*title).to_owned());
+                }
+                ["PERFORMER", performer] => {
+                    let key = if album { "albumartist" } else { "artist" };
+                    tags.insert(key.to_owned(), (*performer).to_owned());
+                }
+                ["REM", "DATE", date] => {
+                    tags.insert("date".to_owned(), (*date).to_owned());
+                }
+                ["REM", "GENRE", genre] => {
+                    tags.insert("genre".to_owned(), (*genre).to_owned());
+                }
+                _ => (),
+            }
+        }
+
+        match (files.as_slice(), tracks.is_empty()) {
+            ([file], false) => Ok(Sheet {
+                file: file.clone(),
+                tags,
+                tracks,
+            }),
+            _ => {
+                error!(
+                    "{} does not describe tracks of a single file",
+                    path.display()
+                );
+                Err(Kind::Cue)?
+            }
+        }
+    }
+
+    /// Length of the `i`th track in seconds, unknown for the last one unless
+    /// the length of the file is given
+    pub fn length(&self, i: usize, total: Option<f64>) -> Option<f64> {
+        let end = match self.tracks.get(i + 1) {
+            Some(next) => Some(next.start),
+            None => total,
+        };
+        end.map(|end| (end - self.tracks[i].start).max(0.0))
+    }
+
+    /// Tags of the `i`th track, those of the sheet over those of the file
+    pub fn metadata(&self, i: usize, file: &Metadata) -> Metadata {
+        let track = &self.tracks[i];
+        let mut meta = file.clone();
+        meta.tags.extend(self.tags.clone());
+        if let Some(artist) = self.tags.get("albumartist") {
+            meta.tags.insert("artist".to_owned(), artist.clone());
+        }
+        meta.tags.extend(track.tags.clone());
+        meta.tags
+            .insert("tracknumber".to_owned(), track.number.to_string());
+        meta.duration = self.length(i, file.duration);
+        meta
+    }
+
+    /// File name of the `i`th track, `01 - Title.<extension>`
+    pub fn file_name(&self, i: usize, extension: &str) -> String {
+        let track = &self.tracks[i];
+        let name = match track.tags.get("title") {
+            Some(title) => format!("{:02} - {}", track.number, title),
+            None => format!("Track {:02}", track.number),
+        };
+        format!("{}.{}", fat_name(&name), extension)
+    }
+
+    /// Output options cutting the `i`th track out of the file and tagging it
+    pub fn args(&self, i: usize, file: &Metadata) -> Vec<String> {
+        let meta = self.metadata(i, file);
+        let mut args = vec!["-ss".to_owned(), format!("{:.6}", self.tracks[i].start)];
+        if let Some(length) = self.length(i, None) {
+            args.push("-t".to_owned());
+            args.push(format!("{:.6}", length));
+        }
+        // Chapters and an embedded sheet describe the whole file
+        args.extend(
+            ["-map_chapters", "-1", "-metadata", "cuesheet="]
+                .iter()
+                .map(|a| (*a).to_owned()),
+        );
+
+        for (key, value) in meta.tags.iter() {
+            let key = match key.as_str() {
+                "tracknumber" => "track",
+                "albumartist" => "album_artist",
+                "album" | "artist" | "title" | "date" | "genre" => key.as_str(),
+                _ => continue,
+            };
+            args.push("-metadata".to_owned());
+            args.push(format!("{}={}", key, value));
+        }
+        args
+    }
+}
+
+// Words of a line, quoted ones taken whole
+fn split(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut started = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        words.push(word);
+    }
+    words
+}
+
+// Seconds in "mm:ss:ff"
+fn parse_time(time: &str) -> Option<f64> {
+    let parts: Vec<f64> = time
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    match parts.as_slice() {
+        [min, sec, frames] => Some(min * 60.0 + sec + frames / FRAMES),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(name: &str, content: &[u8]) -> Result<Sheet> {
+        let dir = std::env::temp_dir().join(format!("muco-cue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let sheet = Sheet::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        sheet
+    }
+
+    #[test]
+    fn quoted_words_are_taken_whole() {
+        assert_eq!(
+            split(r#"  FILE "Kind of Blue.flac"  WAVE"#),
+            ["FILE", "Kind of Blue.flac", "WAVE"]
+        );
+        assert_eq!(split(r#"TITLE """#), ["TITLE", ""]);
+        assert_eq!(split("REM\tDATE 1959"), ["REM", "DATE", "1959"]);
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn times_are_in_frames_of_a_75th() {
+        assert_eq!(parse_time("00:00:00"), Some(0.0));
+        assert_eq!(parse_time("03:25:30"), Some(205.4));
+        assert_eq!(parse_time("74:59:74"), Some(4499.0 + 74.0 / 75.0));
+        assert_eq!(parse_time("03:25"), None);
+        assert_eq!(parse_time("03:xx:00"), None);
+    }
+
+    #[test]
+    fn sheets_are_read() {
+        let sheet = read(
+            "utf8.cue",
+            "\u{feff}REM GENRE Jazz\r\n\
+             REM DATE 1959\r\n\
+             PERFORMER \"Miles Davis\"\r\n\
+             TITLE \"Kind of Blue\"\r\n\
+             FILE \"Kind of Blue.flac\" WAVE\r\n  \
+             TRACK 01 AUDIO\r\n    \
+             TITLE \"So What\"\r\n    \
+             INDEX 01 00:00:00\r\n  \
+             TRACK 02 AUDIO\r\n    \
+             TITLE \"Freddie Freeloader\"\r\n    \
+             PERFORMER \"Miles Davis / Wynton Kelly\"\r\n    \
+             INDEX 00 09:20:00\r\n    \
+             INDEX 01 09:22:30\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(sheet.file.file_name().unwrap(), "Kind of Blue.flac");
+        assert_eq!(sheet.tags["album"], "Kind of Blue");
+        assert_eq!(sheet.tags["albumartist"], "Miles Davis");
+        assert_eq!(sheet.tags["genre"], "Jazz");
+        assert_eq!(sheet.tags["date"], "1959");
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].tags["title"], "So What");
+        assert_eq!(sheet.tracks[1].start, 562.4);
+        assert_eq!(sheet.tracks[1].tags["artist"], "Miles Davis / Wynton Kelly");
+        assert_eq!(sheet.length(0, None), Some(562.4));
+        assert_eq!(sheet.length(1, None), None);
+        assert_eq!(sheet.file_name(0, "opus"), "01 - So What.opus");
+    }
+
+    #[test]
+    fn latin1_sheets_are_read() {
+        let sheet = read(
+            "latin1.cue",
+            b"TITLE \"Caf\xe9 Society\"\nFILE \"Caf\xe9.flac\" WAVE\n\
+              TRACK 01 AUDIO\nTITLE \"D\xe9j\xe0 vu?\"\nINDEX 01 00:00:00\n",
+        )
+        .unwrap();
+
+        assert_eq!(sheet.tags["album"], "Caf\u{e9} Society");
+        assert_eq!(sheet.file.file_name().unwrap(), "Caf\u{e9}.flac");
+        assert_eq!(sheet.file_name(0, "mp3"), "01 - D\u{e9}j\u{e0} vu_.mp3");
+    }
+
+    #[test]
+    fn tracks_without_an_index_start_at_zero() {
+        let sheet = read(
+            "noindex.cue",
+            b"FILE \"a.flac\" WAVE\nTRACK 01 AUDIO\nTRACK 02 AUDIO\nINDEX 01 01:00:00\n",
+        )
+        .unwrap();
+
+        assert_eq!(sheet.tracks[0].start, 0.0);
+        assert_eq!(sheet.tracks[1].start, 60.0);
+        assert_eq!(sheet.file_name(0, "flac"), "Track 01.flac");
+    }
+
+    #[test]
+    fn sheets_of_several_files_are_refused() {
+        let several = read(
+            "several.cue",
+            b"FILE \"a.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+              FILE \"b.flac\" WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n",
+        );
+        assert_eq!(several.unwrap_err().kind(), Kind::Cue);
+
+        let empty = read("empty.cue", b"FILE \"a.flac\" WAVE\n");
+        assert_eq!(empty.unwrap_err().kind(), Kind::Cue);
+    }
+}
@@ -1,11 +1,20 @@
 /// Device Handling
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::config::Config;
+use failure::ResultExt;
+use log::{error, warn};
+use regex::Regex;
+
+use crate::config::{
+    ArtConf, Capabilities, Config, DeviceEntry, Encoder, GainConf, Gapless, Layout,
+};
 use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
 use crate::media::Container;
+use crate::metadata::Metadata;
+use crate::playlist::{Playlist, PlaylistStyle};
+use crate::query::Query;
 use crate::utils::*;
 
 #[derive(Debug)]
@@ -13,27 +22,149 @@ pub struct Device {
     name: String,
     location: PathBuf,
     excludes: Option<String>,
+    exclude: Option<Regex>,
     format: Container,
+    playlists: PlaylistStyle,
+    gain: GainConf,
+    capabilities: Capabilities,
+    encoder: Encoder,
+    layout: Layout,
+    art: ArtConf,
+    companions: Vec<String>,
+    gapless: Gapless,
+    // Playlists selecting the files of a playlist driven device, and those files
+    selection: Option<(Vec<PathBuf>, HashSet<PathBuf>)>,
+    query: Option<Query>,
+    libraries: BTreeMap<String, PathBuf>,
     sources: HashMap<PathBuf, Container>,
 }
 
-pub fn add(
-    mut conf: Config,
-    name: String,
-    location: PathBuf,
-    exclude: Option<String>,
-    format: String,
-) -> Result<()> {
-    let format = Container::try_from(format.as_str())?;
-    conf.add_device(name, location, exclude, format)?;
+pub fn add(mut conf: Config, entry: DeviceEntry) -> Result<()> {
+    if let Some(ref query) = entry.query {
+        Query::try_from(query.as_str())?;
+    }
+    if let Some(ref excludes) = entry.excludes {
+        Regex::new(excludes).context(Kind::Exclude)?;
+    }
+    for library in entry.libraries.keys() {
+        if !conf.get_libraries().iter().any(|(name, _)| name == library) {
+            error!("No library named {}", library);
+            Err(Kind::Nonexistant)?
+        }
+    }
+    conf.add_device(entry)?;
     Ok(())
 }
 
 impl Device {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn excludes(&self) -> Option<&String> {
+        self.excludes.as_ref()
+    }
+
+    /// Whether a library file, relative to its library, must not go to this device
+    pub fn is_excluded(&self, file: &Path) -> bool {
+        match (&self.exclude, file.to_str()) {
+            (Some(exclude), Some(file)) => exclude.is_match(file),
+            _ => false,
+        }
+    }
+
+    /// Whether a library file goes to this device, as far as its playlists are concerned
+    pub fn is_selected(&self, source: &Path) -> bool {
+        match self.selection {
+            Some((_, ref files)) => files.contains(source),
+            None => true,
+        }
+    }
+
+    pub fn query(&self) -> Option<&Query> {
+        self.query.as_ref()
+    }
+
+    /// Whether a library file, relative to its library, matches the query of this device
+    pub fn is_queried(&self, file: &Path, meta: &Metadata) -> bool {
+        match self.query {
+            Some(ref query) => query.matches(file, meta),
+            None => true,
+        }
+    }
+
+    /// Directories of the device the libraries go to, empty when they all go
+    /// to the root
+    pub fn libraries(&self) -> &BTreeMap<String, PathBuf> {
+        &self.libraries
+    }
+
+    /// Where the files of a library go on this device, `None` when the
+    /// library is not synced to it
+    pub fn library_root(&self, library: &str) -> Option<PathBuf> {
+        if self.libraries.is_empty() {
+            return Some(self.location.clone());
+        }
+
+        self.libraries.get(library).map(|dir| {
+            // Directories are given from the root of the device
+            let dir = dir.strip_prefix("/").unwrap_or(dir);
+            self.location.join(dir)
+        })
+    }
+
+    /// Whether a library playlist goes to this device. A playlist driven
+    /// device only gets the playlists selecting its files.
+    pub fn is_playlist_selected(&self, playlist: &Path) -> bool {
+        match self.selection {
+            Some((ref playlists, _)) => playlists.iter().any(|p| p == playlist),
+            None => true,
+        }
+    }
+
     pub fn location(&self) -> &PathBuf {
         &self.location
     }
 
+    pub fn playlists(&self) -> &PlaylistStyle {
+        &self.playlists
+    }
+
+    pub fn gain(&self) -> &GainConf {
+        &self.gain
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn encoder(&self) -> &Encoder {
+        &self.encoder
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn art(&self) -> &ArtConf {
+        &self.art
+    }
+
+    pub fn gapless(&self) -> Gapless {
+        self.gapless
+    }
+
+    /// Whether files with this extension go along with their track
+    pub fn is_companion(&self, file: &Path) -> bool {
+        match file.extension().and_then(|e| e.to_str()) {
+            Some(extension) => self
+                .companions
+                .iter()
+                .any(|c| c.trim_start_matches('.').eq_ignore_ascii_case(extension)),
+            None => false,
+        }
+    }
+
     pub fn source(&self) -> &HashMap<PathBuf, Container> {
         &self.sources
     }
@@ -44,48 +175,90 @@ impl Device {
 
     pub fn get(conf: &Config, name: Option<String>) -> Result<Vec<Device>> {
         match name {
-            Some(name) => match conf.get_devices().iter().find(|(n, _, _, _)| n.eq(&name)) {
-                Some(d) => {
-                    let (n, l, e, f) = d;
-                    let sources: Vec<_> = get_files(n, l).collect();
-                    let sources: HashMap<_, _> = sources
-                        .into_iter()
-                        .map(|(file, container, _base)| (file, container))
-                        .collect();
-
-                    Ok(vec![Device {
-                        name: n.clone(),
-                        location: l.clone(),
-                        excludes: e.clone(),
-                        format: *f,
-                        sources,
-                    }])
-                }
+            Some(name) => match conf.get_devices().iter().find(|d| d.name.eq(&name)) {
+                Some(d) => Ok(vec![Device::scan(conf, d)?]),
                 None => Err(Kind::Nonexistant)?,
             },
-            None => {
-                let devices: Vec<Device> = conf
-                    .get_devices()
-                    .iter()
-                    .map(|(n, l, e, f)| {
-                        let sources: Vec<_> = get_files(n, l).collect();
-                        let sources: HashMap<_, _> = sources
-                            .into_iter()
-                            .map(|(file, container, _base)| (file, container))
-                            .collect();
-
-                        Device {
-                            name: n.clone(),
-                            location: l.clone(),
-                            excludes: e.clone(),
-                            format: *f,
-                            sources,
-                        }
-                    })
-                    .collect();
-
-                Ok(devices)
+            None => conf
+                .get_devices()
+                .iter()
+                .map(|d| Device::scan(conf, d))
+                .collect(),
+        }
+    }
+
+    fn scan(conf: &Config, entry: &DeviceEntry) -> Result<Device> {
+        let mut device = Device::configured(conf, entry)?;
+        device.sources = get_files(&entry.name, &entry.location)
+            .map(|(file, container, _base)| (file, container))
+            .collect();
+        Ok(device)
+    }
+
+    /// The device as configured, without looking at the files it has
+    pub fn configured(conf: &Config, entry: &DeviceEntry) -> Result<Device> {
+        let exclude = match entry.excludes {
+            Some(ref excludes) => Some(Regex::new(excludes).context(Kind::Exclude)?),
+            None => None,
+        };
+        let selection = if entry.only_playlists.is_empty() {
+            None
+        } else {
+            Some(select(conf, &entry.only_playlists))
+        };
+        let query = match entry.query {
+            Some(ref query) => Some(Query::try_from(query.as_str())?),
+            None => None,
+        };
+
+        Ok(Device {
+            name: entry.name.clone(),
+            location: entry.location.clone(),
+            excludes: entry.excludes.clone(),
+            exclude,
+            format: entry.format,
+            playlists: entry.playlists.clone(),
+            gain: entry.gain.clone(),
+            capabilities: entry.capabilities.clone(),
+            encoder: entry.encoder.clone(),
+            layout: entry.layout.clone(),
+            art: entry.art.clone(),
+            companions: entry.companions.clone(),
+            gapless: entry.gapless,
+            selection,
+            query,
+            libraries: entry.libraries.clone(),
+            sources: HashMap::new(),
+        })
+    }
+}
+
+// Reads the selecting playlists, returning where they were found along with
+// the union of their entries. Those which cannot be read are left out, the
+// device getting the files of the others.
+fn select(conf: &Config, playlists: &[PathBuf]) -> (Vec<PathBuf>, HashSet<PathBuf>) {
+    let mut found = Vec::new();
+    let mut files = HashSet::new();
+
+    for playlist in playlists {
+        let path = if playlist.is_absolute() {
+            Some(playlist.clone())
+        } else {
+            conf.get_libraries()
+                .iter()
+                .map(|(_, location)| location.join(playlist))
+                .find(|path| path.is_file())
+        };
+
+        match path.map(|path| (Playlist::read(&path), path)) {
+            Some((Ok(read), path)) => {
+                files.extend(read.entries.into_iter().map(|e| e.path));
+                found.push(path);
             }
+            Some((Err(err), path)) => warn!("Cannot read playlist {}: {}", path.display(), err),
+            None => warn!("Playlist {} not found in any library", playlist.display()),
         }
     }
+
+    (found, files)
 }
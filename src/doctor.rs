@@ -0,0 +1,365 @@
+/// Checking what a sync needs before it runs into what is missing
+///
+/// `muco doctor` looks for the tools muco runs and the encoders ffmpeg was
+/// built with, reads the configuration, checks that libraries can be read
+/// and devices written to, and tells which formats can be produced.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::{Config, Encoder};
+use crate::media::{self, Container, Profile};
+use crate::progress::human_bytes;
+use crate::utils;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Worth knowing, but no sync fails because of it
+    Warning,
+    /// Some sync will fail
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Ok => f.pad("ok"),
+            Status::Warning => f.pad("warning"),
+            Status::Failed => f.pad("FAILED"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub status: Status,
+    pub what: String,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(status: Status, what: &str, detail: String) -> Check {
+        Check {
+            status,
+            what: what.to_owned(),
+            detail,
+        }
+    }
+}
+
+// Tools looked for, with the option printing their version and whether muco
+// needs them
+const TOOLS: [(&str, &str, bool); 3] = [
+    ("ffmpeg", "-version", true),
+    ("lame", "--version", false),
+    ("opusenc", "--version", false),
+];
+
+const ENCODERS: [&str; 4] = ["libmp3lame", "flac", "libopus", "libfdk_aac"];
+
+/// Runs every check, grouped by what they are about
+pub fn run() -> Vec<(&'static str, Vec<Check>)> {
+    let conf = config();
+    let ffmpeg = Ffmpeg {
+        found: media::has_ffmpeg(),
+        encoders: media::encoders(),
+    };
+    let formats = formats(conf.1.as_ref(), &ffmpeg);
+
+    let mut sections = vec![
+        ("Tools", tools()),
+        ("ffmpeg encoders", encoders()),
+        ("Configuration", vec![conf.0]),
+    ];
+    if let Some(conf) = conf.1 {
+        sections.push(("Libraries", libraries(&conf)));
+        sections.push(("Devices", devices(&conf)));
+    }
+    sections.push(("Formats", formats));
+    sections
+}
+
+fn tools() -> Vec<Check> {
+    TOOLS
+        .iter()
+        .map(|(tool, version, needed)| {
+            let output = Command::new(tool)
+                .arg(version)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output();
+            let problem = match output {
+                Ok(output) if output.status.success() => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let version = stdout.lines().next().unwrap_or_default().trim();
+                    return Check::new(Status::Ok, tool, version.to_owned());
+                }
+                Ok(output) => format!("does not run, {}", output.status),
+                Err(_) => "not found in PATH".to_owned(),
+            };
+            // The native backend makes do without ffmpeg for FLAC
+            match *needed && !cfg!(feature = "native") {
+                true => Check::new(
+                    Status::Failed,
+                    tool,
+                    format!("{}, nothing can be transcoded", problem),
+                ),
+                false => Check::new(Status::Warning, tool, problem),
+            }
+        })
+        .collect()
+}
+
+fn encoders() -> Vec<Check> {
+    if !media::has_ffmpeg() {
+        return Vec::new();
+    }
+    let found = match media::encoders() {
+        Some(found) => found,
+        None => {
+            return vec![Check::new(
+                Status::Warning,
+                "ffmpeg",
+                "does not list its encoders".to_owned(),
+            )]
+        }
+    };
+
+    ENCODERS
+        .iter()
+        .map(|encoder| match found.contains(*encoder) {
+            true => Check::new(Status::Ok, encoder, "built in".to_owned()),
+            false => Check::new(Status::Warning, encoder, "not built in".to_owned()),
+        })
+        .collect()
+}
+
+fn config() -> (Check, Option<Config>) {
+    let path = match utils::find_config_file() {
+        Some(path) => path,
+        None => {
+            let check = Check::new(
+                Status::Warning,
+                "muco.toml",
+                "not written yet, add a library or a device first".to_owned(),
+            );
+            return (check, None);
+        }
+    };
+
+    match crate::config::parse_conf(path.clone()) {
+        Ok(conf) => (
+            Check::new(Status::Ok, "muco.toml", path.display().to_string()),
+            Some(conf),
+        ),
+        Err(err) => {
+            let detail = format!("{}: {}", path.display(), err_chain(&err));
+            (Check::new(Status::Failed, "muco.toml", detail), None)
+        }
+    }
+}
+
+fn err_chain(err: &crate::error::MucoError) -> String {
+    let mut causes = Vec::new();
+    let mut cause: Option<&dyn failure::Fail> = Some(err);
+    while let Some(fail) = cause {
+        causes.push(fail.to_string());
+        cause = fail.cause();
+    }
+    causes.join(": ")
+}
+
+fn libraries(conf: &Config) -> Vec<Check> {
+    conf.get_libraries()
+        .iter()
+        .map(|(name, location)| match std::fs::read_dir(location) {
+            Ok(_) => Check::new(Status::Ok, name, location.display().to_string()),
+            Err(err) => Check::new(
+                Status::Failed,
+                name,
+                format!("{}: {}", location.display(), err),
+            ),
+        })
+        .collect()
+}
+
+fn devices(conf: &Config) -> Vec<Check> {
+    conf.get_devices()
+        .iter()
+        .map(|dev| {
+            let location = &dev.location;
+            if !location.exists() {
+                return Check::new(
+                    Status::Warning,
+                    &dev.name,
+                    format!("{} is not mounted", location.display()),
+                );
+            }
+
+            match writable(location) {
+                Ok(()) => {
+                    let free = utils::free_space(location)
+                        .map(|free| format!(", {} free", human_bytes(free)))
+                        .unwrap_or_default();
+                    let detail = format!("{} is writable{}", location.display(), free);
+                    Check::new(Status::Ok, &dev.name, detail)
+                }
+                Err(err) => Check::new(
+                    Status::Failed,
+                    &dev.name,
+                    format!("cannot write to {}: {}", location.display(), err),
+                ),
+            }
+        })
+        .collect()
+}
+
+// Writes and removes a file, as read-only mounts and permissions only show
+// when writing
+fn writable(location: &Path) -> std::io::Result<()> {
+    let probe = location.join(".muco-doctor");
+    std::fs::write(&probe, b"muco")?;
+    std::fs::remove_file(&probe)
+}
+
+// What ffmpeg can do, looked up once
+struct Ffmpeg<'a> {
+    found: bool,
+    /// None when ffmpeg does not list them
+    encoders: Option<&'a BTreeSet<String>>,
+}
+
+fn formats(conf: Option<&Config>, ffmpeg: &Ffmpeg) -> Vec<Check> {
+    [Container::Mp3, Container::Flac, Container::Webm]
+        .iter()
+        .map(|format| {
+            let wanted: Vec<&str> = conf
+                .map(|c| c.get_devices().iter())
+                .into_iter()
+                .flatten()
+                .filter(|d| d.format == *format)
+                .map(|d| d.name.as_str())
+                .collect();
+            let (produced, how) = produced(*format, ffmpeg);
+            let status = match (produced, wanted.is_empty()) {
+                (true, _) => Status::Ok,
+                (false, true) => Status::Warning,
+                (false, false) => Status::Failed,
+            };
+            let detail = match wanted.is_empty() {
+                true => how,
+                false => format!("{}, wanted by {}", how, wanted.join(", ")),
+            };
+            Check::new(status, &format.to_string(), detail)
+        })
+        .collect()
+}
+
+// Whether files can be transcoded to `format`, and how
+fn produced(format: Container, ffmpeg: &Ffmpeg) -> (bool, String) {
+    let profile = match Profile::supports(format) {
+        true => Profile::new(format, &Encoder::default()),
+        false => return (false, "not supported, only copied".to_owned()),
+    };
+    let profile = match profile {
+        Ok(profile) => profile,
+        Err(err) => return (false, err.to_string()),
+    };
+    let encoder = profile.encoder().unwrap_or_default().to_owned();
+
+    if ffmpeg.found && media::lists_encoder(&profile, ffmpeg.encoders) {
+        (true, format!("with ffmpeg ({})", encoder))
+    } else if media::native(&profile) {
+        (true, "natively".to_owned())
+    } else if ffmpeg.found {
+        (false, format!("ffmpeg lacks {}", encoder))
+    } else {
+        (false, "needs ffmpeg".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(encoders: &[&str]) -> BTreeSet<String> {
+        encoders.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn formats_are_produced_with_the_encoders_ffmpeg_has() {
+        let encoders = listing(&["flac", "pcm_s16le"]);
+        let ffmpeg = Ffmpeg {
+            found: true,
+            encoders: Some(&encoders),
+        };
+        assert_eq!(
+            produced(Container::Flac, &ffmpeg),
+            (true, "with ffmpeg (flac)".to_owned())
+        );
+        assert_eq!(
+            produced(Container::Mp3, &ffmpeg),
+            (false, "ffmpeg lacks libmp3lame".to_owned())
+        );
+
+        // Trusted to have them when it does not tell
+        let ffmpeg = Ffmpeg {
+            found: true,
+            encoders: None,
+        };
+        assert_eq!(
+            produced(Container::Mp3, &ffmpeg),
+            (true, "with ffmpeg (libmp3lame)".to_owned())
+        );
+    }
+
+    #[test]
+    fn formats_without_ffmpeg() {
+        let ffmpeg = Ffmpeg {
+            found: false,
+            encoders: None,
+        };
+        assert_eq!(
+            produced(Container::Mp3, &ffmpeg),
+            (false, "needs ffmpeg".to_owned())
+        );
+        let flac = match cfg!(feature = "native") {
+            true => (true, "natively".to_owned()),
+            false => (false, "needs ffmpeg".to_owned()),
+        };
+        assert_eq!(produced(Container::Flac, &ffmpeg), flac);
+    }
+
+    #[test]
+    fn formats_wanted_by_a_device_fail() {
+        let conf: Config = toml::from_str(
+            r#"
+            libraries = []
+            [[devices]]
+            name = "Car"
+            location = "/media/car"
+            format = "Mp3"
+            "#,
+        )
+        .unwrap();
+        let encoders = listing(&["flac"]);
+        let ffmpeg = Ffmpeg {
+            found: true,
+            encoders: Some(&encoders),
+        };
+
+        let checks = formats(Some(&conf), &ffmpeg);
+        let checks: Vec<(Status, &str)> = checks
+            .iter()
+            .map(|c| (c.status, c.detail.as_str()))
+            .collect();
+        assert_eq!(
+            checks[0],
+            (Status::Failed, "ffmpeg lacks libmp3lame, wanted by Car")
+        );
+        assert_eq!(checks[1], (Status::Ok, "with ffmpeg (flac)"));
+        assert_eq!(checks[2], (Status::Warning, "not supported, only copied"));
+    }
+}
@@ -1,3 +1,6 @@
+// failure_derive predates the non_local_definitions lint
+#![allow(non_local_definitions)]
+
 use std::fmt;
 use std::fmt::Display;
 
@@ -24,6 +27,42 @@ pub enum MucoErrorKind {
     Serde,
     #[fail(display = "Transcoding error")]
     Transcode,
+    #[fail(display = "Cannot read mount table")]
+    MountTable,
+    #[fail(display = "Hook command failed")]
+    Hook,
+    #[fail(display = "Cannot access transcode cache")]
+    Cache,
+    #[fail(display = "Cannot watch library")]
+    LibraryWatch,
+    #[fail(display = "Some files could not be synced")]
+    Sync,
+    #[fail(display = "Cannot read/write playlist")]
+    Playlist,
+    #[fail(display = "Invalid exclude pattern")]
+    Exclude,
+    #[fail(display = "Cannot read audio metadata")]
+    Metadata,
+    #[fail(display = "Invalid query")]
+    Query,
+    #[fail(display = "Cannot read/write library index")]
+    Index,
+    #[fail(display = "Some files could not be imported")]
+    Import,
+    #[fail(display = "Cannot read/write device manifest")]
+    Manifest,
+    #[fail(display = "Some device files are damaged")]
+    Verify,
+    #[fail(display = "Cannot measure loudness")]
+    Loudness,
+    #[fail(display = "Cannot sort device folder")]
+    Layout,
+    #[fail(display = "Cannot read CUE sheet")]
+    Cue,
+    #[fail(display = "ffmpeg is needed but was not found in PATH")]
+    NoFfmpeg,
+    #[fail(display = "Some checks failed")]
+    Doctor,
 }
 
 impl MucoError {
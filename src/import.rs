@@ -0,0 +1,181 @@
+/// Importing files found only on a device back into a library
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+
+use crate::device::Device;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::library::Library;
+use crate::manifest::Manifest;
+
+/// Device files which do not come from any of the libraries, sorted. Files
+/// recorded in the manifest of the device were synced, wherever the sync put
+/// them. Otherwise a file comes from a library when the library has a file
+/// at the same place, in any format, whether it is excluded from the device
+/// or not.
+pub fn foreign(dev: &Device, lib: &[Library]) -> Vec<PathBuf> {
+    let synced: HashSet<PathBuf> = Manifest::load(dev.location())
+        .files()
+        .map(|(path, _)| dev.location().join(path))
+        .collect();
+
+    let mut origins = HashSet::new();
+    for library in lib {
+        if let Some(root) = dev.library_root(library.name()) {
+            for path in library.files().keys() {
+                if let Ok(file) = path.strip_prefix(library.location()) {
+                    origins.insert(root.join(file).with_extension(""));
+                }
+            }
+        }
+    }
+
+    let mut foreign: Vec<_> = dev
+        .source()
+        .keys()
+        .filter(|path| !synced.contains(*path) && !origins.contains(&path.with_extension("")))
+        .cloned()
+        .collect();
+    foreign.sort();
+    foreign
+}
+
+/// Copies device files into a library, where a sync would have taken them
+/// from. The device copy is only removed when `remove` is set. Files already
+/// in the library are left alone. Returns where the files went.
+pub fn import(
+    dev: &Device,
+    files: &[PathBuf],
+    library: &Library,
+    remove: bool,
+) -> Result<Vec<PathBuf>> {
+    let root = dev
+        .library_root(library.name())
+        .unwrap_or_else(|| dev.location().clone());
+    let mut imported = Vec::new();
+    let mut failed = 0;
+
+    for file in files {
+        let relative = file
+            .strip_prefix(&root)
+            .or_else(|_| file.strip_prefix(dev.location()))
+            .context(Kind::Unknown)?;
+        let dest = library.location().join(relative);
+
+        if dest.exists() {
+            warn!("{} is already in the library, skipping", dest.display());
+            continue;
+        }
+
+        match copy(file, &dest) {
+            Ok(()) => {
+                info!("Imported {} to {}", file.display(), dest.display());
+                if remove {
+                    debug!("Removing {}", file.display());
+                    if let Err(err) = std::fs::remove_file(file) {
+                        warn!("Cannot remove {}: {}", file.display(), err);
+                    }
+                }
+                imported.push(dest);
+            }
+            Err(err) => {
+                error!("Importing {} failed: {}", file.display(), err);
+                let _ = std::fs::remove_file(&dest);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(Kind::Import)?
+    } else {
+        Ok(imported)
+    }
+}
+
+fn copy(file: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent).context(Kind::Import)?;
+    }
+    std::fs::copy(file, dest).context(Kind::Import)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Duplicates};
+
+    fn write(path: &Path, content: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn files_of_the_manifest_are_not_foreign() {
+        let root = std::env::temp_dir().join(format!("muco-import-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (lib, dev) = (root.join("lib"), root.join("dev"));
+        write(&lib.join("Artist/Album/01 One.flac"), b"one");
+        write(&lib.join("Artist/Album/02 Two.flac"), b"two");
+        write(&lib.join("Artist/Live/Live.flac"), b"live");
+        std::fs::create_dir_all(&dev).unwrap();
+
+        let conf: Config = toml::from_str(&format!(
+            r#"
+            libraries = [["Music", "{lib}"]]
+            [[devices]]
+            name = "Music"
+            location = "{lib}"
+            format = "Flac"
+            [[devices]]
+            name = "Car"
+            location = "{dev}"
+            format = "Flac"
+            [devices.layout]
+            max_files = 1
+            "#,
+            lib = lib.display(),
+            dev = dev.display()
+        ))
+        .unwrap();
+        let scanned = Device::get(&conf, Some("Music".to_owned())).unwrap();
+        let library = Library::from_device(&scanned[0], "Music", &lib);
+
+        // Synced the way the layout of the device wants, folders split
+        let car = Device::get(&conf, Some("Car".to_owned())).unwrap();
+        let plan = crate::plan(&car, vec![library.clone()], Duplicates::All).unwrap();
+        let mut manifest = Manifest::default();
+        for job in plan.jobs.iter() {
+            write(&job.dest, &std::fs::read(&job.source).unwrap());
+            manifest
+                .record(&dev, &job.dest, &job.source, job.track)
+                .unwrap();
+        }
+        assert!(dev.join("Artist/Album (2)/02 Two.flac").is_file());
+
+        // A track cut out of a single file album
+        let track = dev.join("Artist/Live/01 - Intro.flac");
+        write(&track, b"intro");
+        manifest
+            .record(&dev, &track, &lib.join("Artist/Live/Live.flac"), Some(1))
+            .unwrap();
+        manifest.save(&dev).unwrap();
+
+        write(&dev.join("Bought/New.flac"), b"new");
+
+        let car = Device::get(&conf, Some("Car".to_owned())).unwrap();
+        assert_eq!(
+            foreign(&car[0], std::slice::from_ref(&library)),
+            vec![dev.join("Bought/New.flac")]
+        );
+
+        let imported = import(&car[0], &[dev.join("Bought/New.flac")], &library, false).unwrap();
+        assert_eq!(imported, vec![lib.join("Bought/New.flac")]);
+        assert!(dev.join("Bought/New.flac").is_file());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
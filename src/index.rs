@@ -0,0 +1,410 @@
+/// Persistent index of library files
+///
+/// Walking a large library and reading the tags of every file takes a while,
+/// all the more over the network. The index keeps what was found under
+/// `$XDG_CACHE_HOME/muco/index` and is refreshed incrementally: only the
+/// directories whose mtime changed are listed again, and only the files whose
+/// size or mtime changed are read again.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use failure::ResultExt;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::loudness::Loudness;
+use crate::media::Container;
+use crate::metadata::{audio_range, Metadata};
+use crate::playlist::is_playlist;
+use crate::utils::hex;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedFile {
+    pub container: Container,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub tags: Metadata,
+    /// SHA-256 of the audio payload, tags left out, only worked out when
+    /// asked for. Kept across refreshes as long as the size and mtime stay.
+    pub hash: Option<String>,
+    /// Only measured when asked for
+    pub loudness: Option<Loudness>,
+}
+
+// Listing of a directory as of its mtime
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Dir {
+    mtime: SystemTime,
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, Container)>,
+    playlists: Vec<PathBuf>,
+    /// Files neither audio nor playlists, cover art, lyrics...
+    others: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Index {
+    version: u32,
+    location: PathBuf,
+    dirs: HashMap<PathBuf, Dir>,
+    files: HashMap<PathBuf, IndexedFile>,
+}
+
+/// What a refresh found out
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+}
+
+impl Index {
+    // Bumped whenever what gets indexed changes, older indexes are rebuilt
+    const VERSION: u32 = 5;
+
+    pub fn new(location: &Path) -> Index {
+        Index {
+            version: Self::VERSION,
+            location: location.to_path_buf(),
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Loads the index of a library, or an empty one when there is none yet
+    pub fn load(name: &str, location: &Path) -> Index {
+        let empty = Index::new(location);
+
+        let bytes = match index_file(name).ok().and_then(|p| std::fs::read(p).ok()) {
+            Some(bytes) => bytes,
+            None => return empty,
+        };
+
+        // Older indexes have another layout, which must not be read past the version
+        if bincode::deserialize::<u32>(&bytes).ok() != Some(Self::VERSION) {
+            debug!("Index of library {} is outdated, rebuilding it", name);
+            return empty;
+        }
+
+        match bincode::deserialize::<Index>(&bytes) {
+            Ok(index) if index.version == Self::VERSION && index.location == location => index,
+            Ok(_) => {
+                debug!("Index of library {} is outdated, rebuilding it", name);
+                empty
+            }
+            Err(err) => {
+                warn!("Cannot read index of library {}: {}", name, err);
+                empty
+            }
+        }
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = index_file(name)?;
+        // Written aside then renamed, an interrupted save must not corrupt the index
+        let partial = path.with_extension("part");
+
+        let file = File::create(&partial).context(Kind::Index)?;
+        bincode::serialize_into(BufWriter::new(file), self).context(Kind::Index)?;
+        std::fs::rename(&partial, &path).context(Kind::Index)?;
+        Ok(())
+    }
+
+    /// Brings the index up to date with the library. A `full` refresh reads
+    /// every file again, whatever its mtime.
+    pub fn refresh(&mut self, full: bool) -> Result<Changes> {
+        // Keep the index as it is when the library is only unmounted
+        if !self.location.is_dir() {
+            Err(Kind::Nonexistant)?
+        }
+
+        let mut old_dirs = std::mem::take(&mut self.dirs);
+        let mut old_files = std::mem::take(&mut self.files);
+        let mut changes = Changes::default();
+        let mut pending = vec![self.location.clone()];
+
+        while let Some(dir) = pending.pop() {
+            // What was indexed below a directory which cannot be read is kept,
+            // rather than the files there looking removed
+            let mtime = match dir.metadata().and_then(|m| m.modified()) {
+                Ok(mtime) => mtime,
+                Err(err) => {
+                    warn!("Cannot read {}: {}", dir.display(), err);
+                    keep(&dir, &mut old_dirs, &mut self.dirs);
+                    keep(&dir, &mut old_files, &mut self.files);
+                    continue;
+                }
+            };
+
+            let listing = match old_dirs.remove(&dir) {
+                Some(listing) if !full && listing.mtime == mtime => listing,
+                old => match list(&dir, mtime) {
+                    Ok(listing) => listing,
+                    Err(err) => {
+                        warn!("Cannot list {}: {}", dir.display(), err);
+                        if let Some(old) = old {
+                            self.dirs.insert(dir.clone(), old);
+                        }
+                        keep(&dir, &mut old_dirs, &mut self.dirs);
+                        keep(&dir, &mut old_files, &mut self.files);
+                        continue;
+                    }
+                },
+            };
+
+            for (path, container) in listing.files.iter() {
+                let meta = match path.metadata() {
+                    Ok(meta) => meta,
+                    // Gone since the directory was listed
+                    Err(_) => continue,
+                };
+                let size = meta.len();
+                let mtime = meta.modified().context(Kind::Index)?;
+
+                let file = match old_files.remove(path) {
+                    Some(old) if !full && old.size == size && old.mtime == mtime => old,
+                    old => {
+                        let mut file = IndexedFile::read(path, *container, size, mtime);
+                        match old {
+                            Some(old) => {
+                                // Same contents, no need to hash or measure it again
+                                if old.size == size && old.mtime == mtime {
+                                    file.hash = old.hash.clone();
+                                    file.loudness = old.loudness;
+                                }
+                                if old != file {
+                                    changes.changed += 1;
+                                }
+                            }
+                            None => changes.added += 1,
+                        }
+                        file
+                    }
+                };
+                self.files.insert(path.clone(), file);
+            }
+
+            pending.extend(listing.dirs.iter().cloned());
+            self.dirs.insert(dir, listing);
+        }
+
+        changes.removed = old_files.len();
+        debug!(
+            "Index of {} refreshed: {:?}",
+            self.location.display(),
+            changes
+        );
+        Ok(changes)
+    }
+
+    pub fn files(&self) -> &HashMap<PathBuf, IndexedFile> {
+        &self.files
+    }
+
+    pub fn playlists(&self) -> Vec<PathBuf> {
+        let mut playlists: Vec<_> = self
+            .dirs
+            .values()
+            .flat_map(|d| d.playlists.iter().cloned())
+            .collect();
+        playlists.sort();
+        playlists
+    }
+
+    /// Files neither audio nor playlists, sorted
+    pub fn others(&self) -> Vec<PathBuf> {
+        let mut others: Vec<_> = self
+            .dirs
+            .values()
+            .flat_map(|d| d.others.iter().cloned())
+            .collect();
+        others.sort();
+        others
+    }
+
+    pub(crate) fn files_mut(&mut self) -> &mut HashMap<PathBuf, IndexedFile> {
+        &mut self.files
+    }
+}
+
+impl IndexedFile {
+    /// Reads the tags of a file. Unreadable tags are left empty.
+    pub fn read(path: &Path, container: Container, size: u64, mtime: SystemTime) -> Self {
+        let tags = Metadata::read(path, container).unwrap_or_else(|err| {
+            warn!("Cannot read tags of {}: {}", path.display(), err);
+            Metadata::default()
+        });
+
+        IndexedFile {
+            container,
+            size,
+            mtime,
+            tags,
+            hash: None,
+            loudness: None,
+        }
+    }
+
+    /// Returns the hash of the audio, working it out the first time. Copies
+    /// of a track tagged differently hash the same.
+    pub fn hash(&mut self, path: &Path) -> Result<&str> {
+        if self.hash.is_none() {
+            let range = audio_range(path, self.container)?;
+            let mut file = File::open(path).context(Kind::Index)?;
+            file.seek(SeekFrom::Start(range.start))
+                .context(Kind::Index)?;
+            let mut hasher = Sha256::new();
+            io::copy(&mut file.take(range.end - range.start), &mut hasher).context(Kind::Index)?;
+            self.hash = Some(hex(&hasher.result()));
+        }
+        Ok(self.hash.as_deref().unwrap_or_default())
+    }
+}
+
+// Moves the entries at or below `dir` from one map to the other
+fn keep<T>(dir: &Path, from: &mut HashMap<PathBuf, T>, to: &mut HashMap<PathBuf, T>) {
+    let below: Vec<PathBuf> = from
+        .keys()
+        .filter(|p| p.starts_with(dir))
+        .cloned()
+        .collect();
+    for path in below {
+        if let Some(entry) = from.remove(&path) {
+            to.insert(path, entry);
+        }
+    }
+}
+
+// Hidden files and directories are skipped, as when walking the library
+fn list(dir: &Path, mtime: SystemTime) -> Result<Dir> {
+    let mut listing = Dir {
+        mtime,
+        dirs: Vec::new(),
+        files: Vec::new(),
+        playlists: Vec::new(),
+        others: Vec::new(),
+    };
+
+    for entry in std::fs::read_dir(dir).context(Kind::Index)? {
+        let entry = entry.context(Kind::Index)?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        let file_type = entry.file_type().context(Kind::Index)?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+
+        if file_type.is_dir() {
+            listing.dirs.push(path);
+        } else if path.is_dir() {
+            // Symlinked directories are not followed
+            continue;
+        } else if let Ok(container) = Container::try_from(extension) {
+            listing.files.push((path, container));
+        } else if is_playlist(&path) {
+            listing.playlists.push(path);
+        } else {
+            listing.others.push(path);
+        }
+    }
+
+    Ok(listing)
+}
+
+fn index_file(name: &str) -> Result<PathBuf> {
+    Ok(xdg::BaseDirectories::with_prefix("muco")
+        .context(Kind::Index)?
+        .place_cache_file(Path::new("index").join(file_name(name)))
+        .context(Kind::Index)?)
+}
+
+// Library names may hold any character, hashed they make a file name no
+// other library shares
+fn file_name(name: &str) -> String {
+    format!("{}.bin", hex(&Sha256::digest(name.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn libraries_get_their_own_index_file() {
+        let names = ["a/b", "a_b", "a:b", "..", ""];
+        let files: std::collections::HashSet<String> =
+            names.iter().map(|name| file_name(name)).collect();
+        assert_eq!(files.len(), names.len());
+        assert!(files
+            .iter()
+            .all(|f| !f.contains('/') && !f.starts_with('.')));
+    }
+
+    #[test]
+    fn unreadable_directories_keep_their_files() {
+        let root = std::env::temp_dir().join(format!("muco-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("A")).unwrap();
+        std::fs::write(root.join("A/a.mp3"), b"a").unwrap();
+        std::fs::write(root.join("b.mp3"), b"b").unwrap();
+
+        let mut index = Index::new(&root);
+        assert_eq!(index.refresh(false).unwrap().added, 2);
+
+        // A directory which is a file now cannot be listed, as one which
+        // cannot be read or whose mount went away
+        let sub = root.join("A");
+        std::fs::remove_dir_all(&sub).unwrap();
+        std::fs::write(&sub, b"").unwrap();
+        let root_mtime = root.metadata().unwrap().modified().unwrap();
+        index.dirs.get_mut(&root).unwrap().mtime = root_mtime;
+        index.dirs.get_mut(&sub).unwrap().mtime = SystemTime::UNIX_EPOCH;
+
+        let changes = index.refresh(false).unwrap();
+        assert_eq!(changes, Changes::default());
+        assert!(index.files().contains_key(&sub.join("a.mp3")));
+        assert!(index.files().contains_key(&root.join("b.mp3")));
+        assert!(index.dirs.contains_key(&sub));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn hashes_last_while_files_stay_the_same() {
+        let root = std::env::temp_dir().join(format!("muco-hash-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("a.mp3");
+        std::fs::write(&path, b"some mp3 audio frames").unwrap();
+
+        let mut index = Index::new(&root);
+        index.refresh(false).unwrap();
+        let hash = index
+            .files_mut()
+            .get_mut(&path)
+            .unwrap()
+            .hash(&path)
+            .unwrap()
+            .to_owned();
+
+        index.refresh(false).unwrap();
+        assert_eq!(index.files()[&path].hash.as_deref(), Some(hash.as_str()));
+        index.refresh(true).unwrap();
+        assert_eq!(index.files()[&path].hash.as_deref(), Some(hash.as_str()));
+
+        std::fs::write(&path, b"other mp3 audio frames, more of them").unwrap();
+        index.refresh(false).unwrap();
+        assert_eq!(index.files()[&path].hash, None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
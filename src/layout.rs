@@ -0,0 +1,359 @@
+/// Fitting device files within the folder and file counts a player reads
+///
+/// Many car stereos only read the first 255 folders of a stick, or the first
+/// 999 files of a folder. Files are moved around on the way to such devices:
+/// deep folders are flattened to one level, `Artist/Album` becoming
+/// `Artist - Album`, and crowded folders are split in `Album (1)`,
+/// `Album (2)`... Names may also be limited to what FAT allows.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use log::{debug, warn};
+
+use crate::config::Layout;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+
+/// Where each of `files`, relative to the device root, goes under `layout`.
+/// `placed` tells where files synced before are on the device, they stay
+/// there as long as the layout allows, rather than moving along as files
+/// are added. Files left where they are are left out.
+pub fn arrange(
+    layout: &Layout,
+    files: &[PathBuf],
+    placed: &HashMap<PathBuf, PathBuf>,
+) -> HashMap<PathBuf, PathBuf> {
+    let mut arranged: BTreeMap<PathBuf, PathBuf> =
+        files.iter().map(|f| (f.clone(), f.clone())).collect();
+
+    if layout.fat_names {
+        for dest in arranged.values_mut() {
+            *dest = dest
+                .iter()
+                .map(|c| fat_name(&c.to_string_lossy()))
+                .collect();
+        }
+    }
+
+    if let Some(max) = layout.max_depth.filter(|max| *max > 0) {
+        for dest in arranged.values_mut() {
+            *dest = flatten(dest, max);
+        }
+    }
+
+    if let Some(max) = layout.max_folders {
+        if folders(arranged.values()) > max {
+            for dest in arranged.values_mut() {
+                *dest = flatten(dest, 1);
+            }
+
+            let count = folders(arranged.values());
+            if count > max {
+                warn!(
+                    "{} folders even flattened, the player will not read past the first {}",
+                    count, max
+                );
+            }
+        }
+    }
+
+    // Files already in their folder, or in one of its parts, stay there
+    let split = layout.max_files.filter(|max| *max > 0);
+    let mut pinned = BTreeSet::new();
+    for (file, dest) in arranged.iter_mut() {
+        let kept = match (placed.get(file), dest.parent()) {
+            (Some(place), Some(folder)) if place.file_name() == dest.file_name() => {
+                let dir = place.parent().unwrap_or_else(|| Path::new(""));
+                dir == folder || split.is_some() && is_part(dir, folder)
+            }
+            _ => false,
+        };
+        if kept {
+            *dest = placed[file].clone();
+            pinned.insert(file.clone());
+        }
+    }
+
+    if let Some(max) = split {
+        let mut taken: HashMap<PathBuf, usize> = HashMap::new();
+        let mut by_folder: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for (file, dest) in arranged.iter() {
+            let parent = dest.parent().unwrap_or_else(|| Path::new(""));
+            if pinned.contains(file) {
+                *taken.entry(parent.to_path_buf()).or_default() += 1;
+            } else {
+                by_folder
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .push(file.clone());
+            }
+        }
+
+        for (folder, mut files) in by_folder {
+            let used = taken.get(&folder).copied().unwrap_or_default();
+            let parted = taken.keys().any(|dir| is_part(dir, &folder));
+            if !parted && used + files.len() <= max {
+                continue;
+            }
+            files.sort_by(|a, b| arranged[a].cmp(&arranged[b]));
+            debug!("Splitting {} in {} files", folder.display(), max);
+
+            // A folder already holding files counts as the first part
+            let first = if used > 0 { 2 } else { 1 };
+            let mut parts = std::iter::once(folder.clone())
+                .filter(|_| used > 0)
+                .chain((first..).map(|part| part_name(&folder, part)));
+            let mut part = parts.next().unwrap_or_default();
+            for file in files {
+                while taken.get(&part).copied().unwrap_or_default() >= max {
+                    part = parts.next().unwrap_or_default();
+                }
+                *taken.entry(part.clone()).or_default() += 1;
+                let dest = arranged.get_mut(&file).unwrap();
+                *dest = part.join(dest.file_name().unwrap_or_default());
+            }
+        }
+    }
+
+    arranged.into_iter().filter(|(f, d)| f != d).collect()
+}
+
+// Folders holding the files, those in between counting too
+fn folders<'a>(files: impl Iterator<Item = &'a PathBuf>) -> usize {
+    let mut folders = BTreeSet::new();
+    for file in files {
+        folders.extend(file.ancestors().skip(1).filter(|a| *a != Path::new("")));
+    }
+    folders.len()
+}
+
+// Merges the folders of `file` below `depth` levels into the last one
+fn flatten(file: &Path, depth: usize) -> PathBuf {
+    match (file.parent(), file.file_name()) {
+        (Some(parent), Some(name)) if parent.components().count() > depth => {
+            let parts: Vec<_> = parent
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            let (kept, merged) = parts.split_at(depth - 1);
+            let mut flat: PathBuf = kept.iter().map(|p| p.as_ref()).collect();
+            flat.push(merged.join(" - "));
+            flat.join(name)
+        }
+        _ => file.to_path_buf(),
+    }
+}
+
+/// `name` with the characters FAT does not allow replaced by `_`, and
+/// without the trailing dots and spaces it drops. A `/` is replaced too so
+/// that the name stays a single component.
+pub fn fat_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.trim_end_matches(['.', ' ']) {
+        "" => "_".to_owned(),
+        trimmed => trimmed.to_owned(),
+    }
+}
+
+fn part_name(folder: &Path, part: usize) -> PathBuf {
+    match folder.file_name() {
+        Some(name) => {
+            let mut name = OsString::from(name);
+            name.push(format!(" ({})", part));
+            folder.with_file_name(name)
+        }
+        None => folder.join(format!("Part {}", part)),
+    }
+}
+
+// Whether `dir` is one of the parts `folder` is split in
+fn is_part(dir: &Path, folder: &Path) -> bool {
+    let name = match dir.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    let number = match folder.file_name() {
+        Some(base) if dir.parent() == folder.parent() => name
+            .strip_prefix(base.to_string_lossy().as_ref())
+            .and_then(|rest| rest.strip_prefix(" ("))
+            .and_then(|rest| rest.strip_suffix(')')),
+        None if dir.parent() == Some(folder) => name.strip_prefix("Part "),
+        _ => None,
+    };
+    number.is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+/// Writes the directory entries of the files of `dir` again in name order,
+/// for players listing files in the order they were written, as FAT keeps
+/// them. Files are moved out then back in one by one. When that fails
+/// partway, the files moved out are put back.
+pub fn resort(dir: &Path) -> Result<()> {
+    let aside = dir.join(".muco-sort");
+    // Left over by an earlier sort which did not get to the end
+    if aside.is_dir() {
+        restore(dir, &aside)?;
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).context(Kind::Layout)? {
+        let entry = entry.context(Kind::Layout)?;
+        if entry.file_type().context(Kind::Layout)?.is_file() {
+            files.push(entry.file_name());
+        }
+    }
+    files.sort();
+
+    debug!("Sorting {}", dir.display());
+    std::fs::create_dir_all(&aside).context(Kind::Layout)?;
+    let sorted = files
+        .iter()
+        .try_for_each(|file| std::fs::rename(dir.join(file), aside.join(file)))
+        .and_then(|()| {
+            files
+                .iter()
+                .try_for_each(|file| std::fs::rename(aside.join(file), dir.join(file)))
+        });
+    if let Err(err) = sorted {
+        if let Err(err) = restore(dir, &aside) {
+            warn!(
+                "Files of {} left in {}: {}",
+                dir.display(),
+                aside.display(),
+                err
+            );
+        }
+        Err(err).context(Kind::Layout)?
+    }
+    std::fs::remove_dir(&aside).context(Kind::Layout)?;
+    Ok(())
+}
+
+// Moves the files of `aside` back to `dir`, then removes it
+fn restore(dir: &Path, aside: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(aside).context(Kind::Layout)? {
+        let entry = entry.context(Kind::Layout)?;
+        std::fs::rename(entry.path(), dir.join(entry.file_name())).context(Kind::Layout)?;
+    }
+    std::fs::remove_dir(aside).context(Kind::Layout)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arranged(layout: &Layout, files: &[&str]) -> BTreeMap<PathBuf, PathBuf> {
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        arrange(layout, &files, &HashMap::new())
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn deep_folders_are_merged_at_the_last_level() {
+        let layout = Layout {
+            max_depth: Some(2),
+            ..Layout::default()
+        };
+        let moved = arranged(&layout, &["A/B/C/D/x.mp3", "A/B/y.mp3", "z.mp3"]);
+        assert_eq!(
+            moved.into_iter().collect::<Vec<_>>(),
+            vec![(
+                PathBuf::from("A/B/C/D/x.mp3"),
+                PathBuf::from("A/B - C - D/x.mp3")
+            )]
+        );
+    }
+
+    #[test]
+    fn names_are_fat_safe() {
+        let layout = Layout {
+            fat_names: true,
+            ..Layout::default()
+        };
+        let moved = arranged(&layout, &["AC/DC: Live?/Hells Bells.mp3", "Mr. /x.mp3"]);
+        assert_eq!(
+            moved[Path::new("AC/DC: Live?/Hells Bells.mp3")],
+            PathBuf::from("AC/DC_ Live_/Hells Bells.mp3")
+        );
+        assert_eq!(moved[Path::new("Mr. /x.mp3")], PathBuf::from("Mr/x.mp3"));
+        assert_eq!(fat_name("..."), "_");
+        assert_eq!(fat_name("AC/DC"), "AC_DC");
+    }
+
+    #[test]
+    fn files_stay_in_the_part_they_were_synced_to() {
+        let layout = Layout {
+            max_files: Some(2),
+            ..Layout::default()
+        };
+        let first = arranged(&layout, &["A/a", "A/b", "A/c"]);
+        assert_eq!(first[Path::new("A/c")], PathBuf::from("A (2)/c"));
+
+        // A file sorting first comes along, the others do not move
+        let placed: HashMap<PathBuf, PathBuf> = first.into_iter().collect();
+        let files: Vec<PathBuf> = ["A/0", "A/a", "A/b", "A/c"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let second = arrange(&layout, &files, &placed);
+        assert_eq!(second[Path::new("A/a")], PathBuf::from("A (1)/a"));
+        assert_eq!(second[Path::new("A/b")], PathBuf::from("A (1)/b"));
+        assert_eq!(second[Path::new("A/c")], PathBuf::from("A (2)/c"));
+        assert_eq!(second[Path::new("A/0")], PathBuf::from("A (2)/0"));
+    }
+
+    #[test]
+    fn folders_holding_files_count_as_the_first_part() {
+        let layout = Layout {
+            max_files: Some(2),
+            ..Layout::default()
+        };
+        let placed: HashMap<PathBuf, PathBuf> = ["A/a", "A/b"]
+            .iter()
+            .map(|f| (PathBuf::from(f), PathBuf::from(f)))
+            .collect();
+        let files: Vec<PathBuf> = ["A/a", "A/b", "A/c"].iter().map(PathBuf::from).collect();
+        let moved = arrange(&layout, &files, &placed);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[Path::new("A/c")], PathBuf::from("A (2)/c"));
+    }
+
+    #[test]
+    fn places_the_layout_no_longer_allows_are_left() {
+        let layout = Layout {
+            max_depth: Some(1),
+            ..Layout::default()
+        };
+        let placed: HashMap<PathBuf, PathBuf> =
+            vec![(PathBuf::from("A/B/x"), PathBuf::from("A/B/x"))]
+                .into_iter()
+                .collect();
+        let files = vec![PathBuf::from("A/B/x")];
+        let moved = arrange(&layout, &files, &placed);
+        assert_eq!(moved[Path::new("A/B/x")], PathBuf::from("A - B/x"));
+    }
+
+    #[test]
+    fn files_left_aside_are_put_back() {
+        let dir = std::env::temp_dir().join(format!("muco-resort-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".muco-sort")).unwrap();
+        std::fs::write(dir.join("b"), b"b").unwrap();
+        std::fs::write(dir.join(".muco-sort").join("a"), b"a").unwrap();
+
+        resort(&dir).unwrap();
+        assert!(dir.join("a").is_file() && dir.join("b").is_file());
+        assert!(!dir.join(".muco-sort").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,96 +14,1157 @@
 //! Muco can manage multiple libraries, devices, formats, and can
 //! transcode on the fly.
 
-use std::process::Command;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
-use log::{error, debug};
 use failure::ResultExt;
+use log::{debug, error, warn};
 
 pub(crate) mod utils;
 
+pub mod cache;
 pub mod config;
+pub mod cue;
 pub mod device;
+pub mod doctor;
 pub mod error;
+pub mod import;
+pub mod index;
+pub mod layout;
 pub mod library;
+pub mod loudness;
+pub mod manifest;
 pub mod media;
+pub mod metadata;
+#[cfg(feature = "native")]
+pub mod native;
+pub mod playlist;
+pub mod preset;
+pub mod progress;
+pub mod query;
+pub mod verify;
+pub mod watch;
 
-use config::Config;
+use cache::Cache;
+use config::{Config, Duplicates, GainMode, Gapless, Sidecar};
+use cue::Sheet;
 use device::Device;
-use error::{MucoErrorKind as Kind, MucoResult as Result};
+use error::{MucoError, MucoErrorKind, MucoErrorKind as Kind, MucoResult as Result};
+use index::IndexedFile;
 use library::Library;
-use media::Container;
+use manifest::Manifest;
+use media::Profile;
+use playlist::{Entry, Playlist, PlaylistFormat, PlaylistStyle};
+use progress::Progress;
+
+/// A file to be written to a device
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub device: String,
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    pub action: Action,
+    /// Size of the source in bytes
+    pub size: u64,
+    /// Number of the track cut out of a single file album
+    pub track: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Copy,
+    Transcode(Profile),
+    /// Write the playlist with its entries rewritten to their device path.
+    /// Entries whose file did not make it to the device are dropped.
+    Playlist {
+        entries: Vec<(Entry, PathBuf)>,
+        style: PlaylistStyle,
+        root: PathBuf,
+    },
+    /// Write the picture of a folder, taken from an image or from the art
+    /// embedded in a track, scaled down to `max_size` pixels
+    Art {
+        max_size: Option<u32>,
+    },
+}
+
+/// Files to be written to the devices, along with those they already have
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+    pub jobs: Vec<Job>,
+    pub skipped: Vec<Job>,
+    /// Files left out because another source goes to the same place on the
+    /// device, along with that source
+    pub collisions: Vec<(Job, PathBuf)>,
+    /// Files left out for lack of space on the device
+    pub unfit: Vec<Job>,
+    /// Location of each device planned for
+    pub roots: HashMap<String, PathBuf>,
+    /// Files synced before to another place on the device, removed once
+    /// the job bringing them to their new place is done
+    pub moved: Vec<(Job, PathBuf)>,
+    /// Folders whose files are to be written again in name order once
+    /// synced, see [`layout::resort`]
+    pub resort: Vec<PathBuf>,
+    /// Files which cannot be synced, e.g. to a format muco cannot encode,
+    /// along with why. Their action is left a copy.
+    pub failed: Vec<(Job, MucoErrorKind)>,
+}
+
+/// Works out what has to be written to the devices
+pub fn plan(dev: &[Device], mut lib: Vec<Library>, duplicates: Duplicates) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let mut playlists = Vec::new();
+    for d in dev.iter() {
+        plan.roots.insert(d.name().to_owned(), d.location().clone());
+    }
+
+    // Copies left out, along with the copy synced instead
+    let mut aliases: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for library in lib.iter() {
+        for (worse, best) in library.superseded() {
+            debug!("Skipping {}, {} is better", worse.display(), best.display());
+            aliases.insert(worse, best);
+        }
+    }
+    if duplicates == Duplicates::First {
+        for group in library::duplicates(&mut lib) {
+            for copy in group[1..].iter() {
+                debug!(
+                    "Skipping {}, same as {}",
+                    copy.display(),
+                    group[0].display()
+                );
+                aliases
+                    .entry(copy.clone())
+                    .or_insert_with(|| group[0].clone());
+            }
+        }
+    }
+
+    let mut planned = Vec::new();
+    // Where the files synced before are on each device, by source
+    let mut synced: HashMap<&str, HashMap<PathBuf, Vec<PathBuf>>> = HashMap::new();
+
+    // Pictures by folder, and tracks with embedded art
+    let mut images: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut with_art = HashSet::new();
+    // Other files by their path without extension
+    let mut companions: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    // CUE sheets by the single file album they describe
+    let mut sheets: HashMap<PathBuf, Sheet> = HashMap::new();
 
-pub fn sync(_conf: &Config, dev: Vec<Device>, lib: Vec<Library>) -> Result<()> {
     for library in lib {
         let lib_base = library.location().clone();
+        for other in library.others() {
+            if cue::is_cue(&other) {
+                match Sheet::read(&other) {
+                    Ok(mut sheet) => match described(&sheet, library.files()) {
+                        Some(file) => {
+                            sheet.file = file;
+                            sheets.insert(sheet.file.clone(), sheet);
+                        }
+                        None => debug!(
+                            "{} refers to {}, which is not in the library",
+                            other.display(),
+                            sheet.file.display()
+                        ),
+                    },
+                    Err(_) => warn!("Syncing the album of {} as one file", other.display()),
+                }
+            }
+            companions
+                .entry(other.with_extension(""))
+                .or_default()
+                .push(other);
+        }
+        for image in library.images() {
+            if let Some(folder) = image.parent() {
+                images
+                    .entry(folder.to_path_buf())
+                    .or_default()
+                    .push(image.clone());
+            }
+        }
+        with_art.extend(
+            library
+                .files()
+                .iter()
+                .filter(|(_, f)| f.tags.art)
+                .map(|(p, _)| p.clone()),
+        );
+        for playlist in library.playlists() {
+            playlists.push((
+                library.name().to_owned(),
+                lib_base.clone(),
+                playlist.clone(),
+            ));
+        }
 
-        library.source().into_iter().for_each(|(path, format)| {
-            let stripped_lib_file = path.strip_prefix(&lib_base).ok();
+        let mut sources: Vec<_> = library.files().iter().collect();
+        sources.sort_by(|a, b| a.0.cmp(b.0));
 
-            for dev in dev.iter() {
-                let dev_base = dev.location().clone();
-                let dev_format = dev.format();
-                let dev_format_str: &'static str = dev.format().into();
-                let dev_parent = dev_base.join(stripped_lib_file.and_then(|f| f.parent()).unwrap());
-                let dev_dest =
-                    dev_base.join(stripped_lib_file.unwrap().with_extension(dev_format_str));
+        for (path, file) in sources {
+            if aliases.contains_key(path) {
+                continue;
+            }
 
-                let found = dev.source().iter().any(|(dev_file, _)| {
-                    let filename = dev_file.strip_prefix(&dev_base).unwrap();
-                    stripped_lib_file.unwrap().eq(filename)
-                });
+            let stripped_lib_file = path.strip_prefix(&lib_base).context(Kind::Unknown)?;
 
-                if found {
-                    continue;
-                } else if dev_format.eq(&format) {
-                    println!("Copy to {}", dev_dest.display());
-                    debug!("Copying: {} to {}", path.display(), dev_dest.display());
-                    herr_exit!(std::fs::copy(&path, &dev_dest).context(Kind::Unknown), 1);
-                } else {
-                    println!("Transcoding to: {}", dev_dest.display());
-                    debug!("Creating directories: {}", dev_parent.display());
-                    herr_exit!(std::fs::create_dir_all(&dev_parent), 1);
-
-                    match dev_format {
-                        Container::Mp3 => {
-                            let output = Command::new("ffmpeg")
-                                .current_dir(".")
-                                .arg("-i")
-                                .arg(&path)
-                                .arg("-vn")
-                                .arg("-codec:a")
-                                .arg("libmp3lame")
-                                .arg("-b:a")
-                                .arg("320k")
-                                .arg(&dev_dest)
-                                .output();
-
-                            herr_exit!(output, 1);
-                        }
-                        Container::Flac => {
-                            let output = Command::new("ffmpeg")
-                                .current_dir(".")
-                                .arg("-i")
-                                .arg(&path)
-                                .arg("-acodec")
-                                .arg("flac")
-                                .arg("-bits_per_raw_sample")
-                                .arg("24")
-                                .arg("-ar")
-                                .arg("48000")
-                                .arg(&dev_dest)
-                                .output();
-
-                            herr_exit!(output, 1);
+            // A single file album goes as its tracks, cut out of it, to the
+            // devices whose format can be encoded, and whole to the others
+            let mut tracks = vec![(
+                stripped_lib_file.to_path_buf(),
+                Cow::Borrowed(&file.tags),
+                file.size,
+                Vec::new(),
+                None,
+            )];
+            if let Some(sheet) = sheets.get(path) {
+                let extension = path.extension().unwrap_or_default().to_string_lossy();
+                tracks.extend((0..sheet.tracks.len()).map(|i| {
+                    let meta = sheet.metadata(i, &file.tags);
+                    let share = match (meta.duration, file.tags.duration) {
+                        (Some(length), Some(total)) if total > 0.0 => length / total,
+                        _ => 1.0 / sheet.tracks.len() as f64,
+                    };
+                    (
+                        stripped_lib_file.with_file_name(sheet.file_name(i, &extension)),
+                        Cow::Owned(meta),
+                        (file.size as f64 * share) as u64,
+                        sheet.args(i, &file.tags),
+                        Some(sheet.tracks[i].number),
+                    )
+                }));
+            }
+            let cut = |d: &Device| sheets.contains_key(path) && Profile::supports(d.format());
+
+            for (stripped_lib_file, meta, size, split, track) in tracks.iter() {
+                // A playlist may refer to a copy which was left out
+                let selected = |d: &Device| {
+                    d.is_selected(path)
+                        || aliases
+                            .iter()
+                            .any(|(copy, kept)| kept == path && d.is_selected(copy))
+                };
+                let wanted = |d: &&Device| {
+                    cut(d) == track.is_some()
+                        && d.library_root(library.name()).is_some()
+                        && !d.is_excluded(stripped_lib_file)
+                        && selected(d)
+                        && d.is_queried(stripped_lib_file, meta)
+                };
+                for dev in dev.iter().filter(wanted) {
+                    let dev_base = dev.library_root(library.name()).unwrap_or_default();
+                    let dev_format_str: &'static str = dev.format().into();
+                    let dev_dest = dev_base.join(stripped_lib_file.with_extension(dev_format_str));
+
+                    let mut job = Job {
+                        device: dev.name().to_owned(),
+                        source: path.clone(),
+                        dest: dev_dest,
+                        action: Action::Copy,
+                        size: *size,
+                        track: *track,
+                    };
+                    // One file the device cannot get does not hold the others back
+                    match action(dev, file, split) {
+                        Ok(action) => {
+                            job.action = action;
+                            planned.push(job);
                         }
-                        _ => {
-                            error!("Transcoding not supported to {:?}", dev_format);
-                            herr_exit!(Err(Kind::Transcode.into()) as Result<()>, 1);
+                        Err(err) => {
+                            error!("Cannot sync {} to {}: {}", path.display(), dev.name(), err);
+                            plan.failed.push((job, err.kind()));
                         }
                     }
                 }
             }
+        }
+    }
+
+    // Files are moved around once all those a device gets are known
+    for dev in dev.iter() {
+        let (mut jobs, rest): (Vec<_>, Vec<_>) =
+            planned.into_iter().partition(|j| j.device == dev.name());
+        planned = rest;
+
+        let root = dev.location();
+        let layout = dev.layout();
+        let previous = synced.entry(dev.name()).or_default();
+        for (file, record) in Manifest::load(root).files() {
+            if root.join(file).is_file() {
+                previous
+                    .entry(record.source.clone())
+                    .or_default()
+                    .push(file.clone());
+            }
+        }
+
+        let mut files = Vec::new();
+        let mut placed = HashMap::new();
+        for job in jobs.iter() {
+            if let Ok(file) = job.dest.strip_prefix(root) {
+                files.push(file.to_path_buf());
+                if let Some(place) = previous.get(&job.source).and_then(|p| synced_as(p, file)) {
+                    placed
+                        .entry(file.to_path_buf())
+                        .or_insert_with(|| place.clone());
+                }
+            }
+        }
+        let moved = layout::arrange(layout, &files, &placed);
+        for job in jobs.iter_mut() {
+            if let Some(dest) = job.dest.strip_prefix(root).ok().and_then(|f| moved.get(f)) {
+                job.dest = root.join(dest);
+            }
+        }
+
+        // Where each device file comes from, the first library claiming it
+        // wins, wherever the layout put the others
+        let mut claimed: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut jobs: Vec<Job> = jobs
+            .into_iter()
+            .filter_map(|job| match claimed.get(&job.dest) {
+                Some(kept) => {
+                    debug!(
+                        "{} and {} both go to {}, skipping the latter",
+                        kept.display(),
+                        job.source.display(),
+                        job.dest.display()
+                    );
+                    plan.collisions.push((job, kept.clone()));
+                    None
+                }
+                None => {
+                    claimed.insert(job.dest.clone(), job.source.clone());
+                    Some(job)
+                }
+            })
+            .collect();
+        if layout.sorted {
+            jobs.sort_by(|a, b| a.dest.cmp(&b.dest));
+        }
+
+        // Folders getting new files after the old ones have to be sorted again
+        let filled: HashSet<&Path> = dev.source().keys().filter_map(|f| f.parent()).collect();
+        let mut resort = BTreeSet::new();
+        for job in jobs {
+            if dev.source().contains_key(&job.dest) {
+                plan.skipped.push(job);
+            } else {
+                match job.dest.parent() {
+                    Some(dir) if layout.sorted && filled.contains(dir) => {
+                        resort.insert(dir.to_path_buf());
+                    }
+                    _ => (),
+                }
+                plan.jobs.push(job);
+            }
+        }
+        plan.resort.extend(resort);
+    }
+
+    // Pictures go next to the tracks, wherever these ended up
+    for dev in dev.iter() {
+        let art = dev.art();
+        if art.sidecar == Sidecar::None {
+            continue;
+        }
+
+        let mut folders: BTreeMap<&Path, Vec<&Job>> = BTreeMap::new();
+        for job in plan.jobs.iter().chain(plan.skipped.iter()) {
+            if let (true, Some(folder)) = (job.device == dev.name(), job.dest.parent()) {
+                folders.entry(folder).or_default().push(job);
+            }
+        }
+
+        let mut jobs = Vec::new();
+        for (folder, mut tracks) in folders {
+            tracks.sort_by(|a, b| a.dest.cmp(&b.dest));
+            let embedded = tracks
+                .iter()
+                .find(|t| art.sidecar == Sidecar::Extract && with_art.contains(&t.source))
+                .map(|t| t.source.clone());
+            let source = match embedded.or_else(|| cover(&images, &tracks)) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let dest = folder.join(&art.name);
+            let size = source.metadata().map(|m| m.len()).unwrap_or_default();
+            let job = Job {
+                device: dev.name().to_owned(),
+                source,
+                dest,
+                action: Action::Art {
+                    max_size: art.max_size,
+                },
+                size,
+                track: None,
+            };
+            jobs.push((job.dest.exists(), job));
+        }
+
+        for (exists, job) in jobs {
+            if exists {
+                plan.skipped.push(job);
+            } else {
+                plan.jobs.push(job);
+            }
+        }
+    }
+
+    // Companions follow their track, wherever it ended up
+    for dev in dev.iter() {
+        let mut jobs = Vec::new();
+        for track in plan.jobs.iter().chain(plan.skipped.iter()) {
+            // Those of a single file album describe the whole of it
+            if track.device != dev.name()
+                || !matches!(track.action, Action::Copy | Action::Transcode(_))
+                || track.track.is_some()
+            {
+                continue;
+            }
+
+            let found = companions.get(&track.source.with_extension(""));
+            for companion in found.into_iter().flatten().filter(|c| dev.is_companion(c)) {
+                let dest = track
+                    .dest
+                    .with_extension(companion.extension().unwrap_or_default());
+                let size = companion.metadata().map(|m| m.len()).unwrap_or_default();
+                jobs.push(Job {
+                    device: dev.name().to_owned(),
+                    source: companion.clone(),
+                    dest,
+                    action: Action::Copy,
+                    size,
+                    track: None,
+                });
+            }
+        }
+
+        for job in jobs {
+            if job.dest.exists() {
+                plan.skipped.push(job);
+            } else {
+                plan.jobs.push(job);
+            }
+        }
+    }
+
+    // Files synced before to another place are not left behind
+    for dev in dev.iter() {
+        let previous = match synced.get(dev.name()) {
+            Some(previous) => previous,
+            None => continue,
+        };
+        let root = dev.location();
+        let tracks: Vec<&Job> = plan
+            .jobs
+            .iter()
+            .chain(plan.skipped.iter())
+            .filter(|job| job.device == dev.name())
+            .filter(|job| matches!(job.action, Action::Copy | Action::Transcode(_)))
+            .collect();
+        let dests: HashSet<&PathBuf> = tracks.iter().map(|job| &job.dest).collect();
+
+        let mut moved = Vec::new();
+        for job in tracks.iter() {
+            let places = previous.get(&job.source).into_iter().flatten();
+            for place in places.map(|p| root.join(p)) {
+                let same = synced_as(std::slice::from_ref(&place), &job.dest).is_some();
+                if same && !dests.contains(&place) {
+                    moved.push(((*job).clone(), place));
+                }
+            }
+        }
+        plan.moved.extend(moved);
+    }
+
+    // Playlists go last, once it is known where their entries end up
+    for dev in dev.iter() {
+        let style = dev.playlists();
+        if style.format == PlaylistFormat::None {
+            continue;
+        }
+
+        // A single file album has a dest for every track
+        let mut dests: HashMap<&PathBuf, Vec<&PathBuf>> = HashMap::new();
+        for job in plan.jobs.iter().chain(plan.skipped.iter()) {
+            if job.device == dev.name() && !matches!(job.action, Action::Art { .. }) {
+                dests.entry(&job.source).or_default().push(&job.dest);
+            }
+        }
+        for tracks in dests.values_mut() {
+            tracks.sort();
+        }
+
+        let mut jobs = Vec::new();
+        for (name, lib_base, path) in playlists.iter() {
+            let root = match dev.library_root(name) {
+                Some(root) => root,
+                None => continue,
+            };
+            let stripped = path.strip_prefix(lib_base).context(Kind::Unknown)?;
+            if dev.is_excluded(stripped) || !dev.is_playlist_selected(path) {
+                continue;
+            }
+
+            let playlist = match Playlist::read(path) {
+                Ok(playlist) => playlist,
+                Err(err) => {
+                    warn!("Not syncing playlist {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            let entries = playlist
+                .entries
+                .into_iter()
+                .flat_map(|e| {
+                    // The better copy of a worse one may be a duplicate in turn
+                    let mut path = &e.path;
+                    for _ in 0..2 {
+                        path = aliases.get(path).unwrap_or(path);
+                    }
+                    let found = dests.get(path).cloned().unwrap_or_default();
+                    found.into_iter().map(move |dest| (e.clone(), dest.clone()))
+                })
+                .collect();
+
+            let mut dest = stripped.with_extension(style.extension());
+            if dev.layout().fat_names {
+                dest = dest
+                    .iter()
+                    .map(|c| layout::fat_name(&c.to_string_lossy()))
+                    .collect();
+            }
+            jobs.push(Job {
+                device: dev.name().to_owned(),
+                source: path.clone(),
+                dest: root.join(dest),
+                action: Action::Playlist {
+                    entries,
+                    style: style.clone(),
+                    root: dev.location().clone(),
+                },
+                size: 0,
+                track: None,
+            });
+        }
+        plan.jobs.extend(jobs);
+    }
+
+    Ok(plan)
+}
+
+// The library file a sheet describes. Rippers name it before it gets
+// converted, the sheet of `Album.flac` often saying `Album.wav`.
+fn described(sheet: &Sheet, files: &HashMap<PathBuf, IndexedFile>) -> Option<PathBuf> {
+    if files.contains_key(&sheet.file) {
+        return Some(sheet.file.clone());
+    }
+    let stem = sheet.file.with_extension("");
+    files
+        .keys()
+        .filter(|f| f.with_extension("") == stem)
+        .min()
+        .cloned()
+}
+
+// The one of the device files synced from a source which is the same file
+// as `dest`, whatever the layout did to its name
+fn synced_as<'a>(places: &'a [PathBuf], dest: &Path) -> Option<&'a PathBuf> {
+    let name = |p: &Path| {
+        p.file_name()
+            .map(|n| layout::fat_name(&n.to_string_lossy()))
+    };
+    places.iter().find(|p| name(p) == name(dest))
+}
+
+// Picture of the library folder of the tracks, a cover rather than a
+// back or booklet scan when there are several
+fn cover(images: &HashMap<PathBuf, Vec<PathBuf>>, tracks: &[&Job]) -> Option<PathBuf> {
+    let found = tracks
+        .iter()
+        .filter_map(|t| images.get(t.source.parent()?))
+        .next()?;
+    let named = |p: &&PathBuf| {
+        let stem = p.file_stem().map(|s| s.to_string_lossy().to_lowercase());
+        matches!(
+            stem.as_deref(),
+            Some("cover") | Some("folder") | Some("front")
+        )
+    };
+    found.iter().find(named).or_else(|| found.first()).cloned()
+}
+
+/// Syncs the libraries to the devices, reporting to `progress` as it goes.
+/// A file which fails to sync does not stop the others from being synced.
+pub fn sync(
+    conf: &Config,
+    dev: Vec<Device>,
+    lib: Vec<Library>,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    let plan = match plan(&dev, lib, conf.get_sync().duplicates) {
+        Ok(plan) => plan,
+        Err(err) => {
+            progress.aborted(&err);
+            progress.done();
+            return Err(err);
+        }
+    };
+    execute(conf, plan, progress)
+}
+
+// How a library file gets to a device
+// `split` cuts a track out of the file, which has to be encoded again then
+pub(crate) fn action(dev: &Device, file: &IndexedFile, split: &[String]) -> Result<Action> {
+    let format = dev.format();
+    let gain = dev.gain();
+    let fit = media::fit(dev.capabilities(), &file.tags, format);
+    let loudness = file.loudness.filter(|_| gain.mode != GainMode::None);
+    let strip = dev.art().strip && file.tags.art;
+
+    // Files already in the device format are only encoded again when they
+    // have to be and can be, otherwise they are copied, possibly with tags
+    let encode =
+        !fit.is_empty() || !split.is_empty() || gain.mode == GainMode::Apply && loudness.is_some();
+    if file.container == format && !(encode && Profile::supports(format)) {
+        let mut profile = Profile::copy(format);
+        match loudness {
+            Some(loudness) if gain.mode == GainMode::Tags => {
+                profile = profile.with_args(loudness.tag_args(gain));
+            }
+            _ if !strip => return Ok(Action::Copy),
+            _ => (),
+        }
+        if strip {
+            profile = profile.without_art();
+        }
+        if dev.gapless() == Gapless::None {
+            profile = profile.without_gapless();
+        }
+        return Ok(Action::Transcode(profile));
+    }
+
+    let mut profile = Profile::new(format, dev.encoder())?
+        .with_args(split.to_vec())
+        .with_args(fit);
+    profile = match loudness {
+        Some(loudness) if gain.mode == GainMode::Apply => {
+            profile.with_args(loudness.filter_args(gain, file.tags.sample_rate))
+        }
+        Some(loudness) => profile.with_args(loudness.tag_args(gain)),
+        None => profile,
+    };
+    if strip {
+        profile = profile.without_art();
+    }
+    if dev.gapless() == Gapless::None {
+        profile = profile.without_gapless();
+    }
+    Ok(Action::Transcode(profile))
+}
+
+/// Syncs the given device files again from the libraries, whether they are
+/// there already or not. Returns the files no library has a source for.
+pub fn resync(
+    conf: &Config,
+    dev: Device,
+    lib: Vec<Library>,
+    files: &[PathBuf],
+    progress: &mut dyn Progress,
+) -> Result<Vec<PathBuf>> {
+    let mut plan = plan(&[dev], lib, conf.get_sync().duplicates)?;
+    let mut jobs = std::mem::take(&mut plan.jobs);
+    jobs.append(&mut plan.skipped);
+    jobs.retain(|job| files.contains(&job.dest));
+    plan.failed.retain(|(job, _)| files.contains(&job.dest));
+
+    let orphans = files
+        .iter()
+        .filter(|file| !jobs.iter().any(|job| &job.dest == *file))
+        .cloned()
+        .collect();
+
+    let plan = Plan {
+        jobs,
+        roots: plan.roots,
+        failed: plan.failed,
+        ..Plan::default()
+    };
+    execute(conf, plan, progress)?;
+    Ok(orphans)
+}
+
+/// Clones a device to another, the libraries being offline. Files are
+/// transcoded where the formats differ, and left out when they would not fit
+/// in the free space of the target.
+pub fn clone_device(
+    conf: &Config,
+    from: &Device,
+    to: Device,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    // Libraries stay in their directory when both devices map them to one
+    let lib: Vec<_> = if to.libraries().is_empty() {
+        vec![Library::from_device(from, from.name(), from.location())]
+    } else {
+        from.libraries()
+            .keys()
+            .filter_map(|name| Some(Library::from_device(from, name, &from.library_root(name)?)))
+            .collect()
+    };
+    if lib.is_empty() {
+        error!(
+            "{} maps libraries to directories but {} does not",
+            to.name(),
+            from.name()
+        );
+        Err(Kind::Nonexistant)?
+    }
+
+    let durations: HashMap<PathBuf, Option<f64>> = lib
+        .iter()
+        .flat_map(|l| l.files().iter())
+        .map(|(path, file)| (path.clone(), file.tags.duration))
+        .collect();
+    let mut free = utils::free_space(to.location())?;
+
+    let mut plan = plan(std::slice::from_ref(&to), lib, conf.get_sync().duplicates)?;
+    let (jobs, unfit) = plan.jobs.into_iter().partition(|job| {
+        let size = match job.action {
+            Action::Copy => job.size,
+            Action::Transcode(ref profile) => {
+                profile.estimate(job.size, durations.get(&job.source).cloned().flatten())
+            }
+            Action::Playlist { .. } | Action::Art { .. } => 0,
+        };
+        if size <= free {
+            free -= size;
+            true
+        } else {
+            false
+        }
+    });
+    plan.jobs = jobs;
+    plan.unfit = unfit;
+
+    execute(conf, plan, progress)
+}
+
+// Files written to a device between two saves of its manifest
+const MANIFEST_EVERY: usize = 50;
+
+/// Writes the files of a plan, reporting to `progress` as it goes. A file
+/// which fails to sync does not stop the others from being synced.
+pub fn execute(conf: &Config, plan: Plan, progress: &mut dyn Progress) -> Result<()> {
+    let failing = plan.failed.iter().map(|(job, _)| job);
+    progress.planned(
+        plan.jobs.len() + plan.failed.len(),
+        plan.jobs.iter().chain(failing).map(|j| j.size).sum(),
+    );
+
+    for (job, kind) in plan.failed.iter() {
+        progress.failed(job, &MucoError::from(*kind));
+    }
+
+    for job in plan.skipped.iter() {
+        progress.skipped(job);
+    }
+    for (job, kept) in plan.collisions.iter() {
+        progress.collided(job, kept);
+    }
+    for job in plan.unfit.iter() {
+        progress.unfit(job);
+    }
+
+    // Better not to start than to have every transcode fail along the way
+    let unable: Vec<&Job> = plan
+        .jobs
+        .iter()
+        .filter(|job| match job.action {
+            Action::Transcode(ref profile) => !media::can_transcode(profile),
+            Action::Art { max_size } => {
+                !is_plain_copy(&job.source, &job.dest, max_size) && !media::has_ffmpeg()
+            }
+            _ => false,
         })
+        .collect();
+    if !unable.is_empty() {
+        let err = match media::require_ffmpeg(&format!("sync {} of the files", unable.len())) {
+            Err(err) => err,
+            Ok(()) => {
+                error!(
+                    "ffmpeg lacks the encoders {} of the files need, see `muco doctor`",
+                    unable.len()
+                );
+                Kind::Transcode.into()
+            }
+        };
+        for job in unable {
+            progress.failed(job, &err);
+        }
+        progress.done();
+        return Err(err);
+    }
+
+    // Transcodes are shared between devices wanting the same output
+    let cache = match Cache::open(conf.get_cache()) {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            warn!("Transcode cache unavailable: {}", err);
+            None
+        }
+    };
+
+    // Saved along the way, so that a sync which gets interrupted does not
+    // leave files it wrote unrecorded
+    let mut manifests: HashMap<&str, Manifest> = HashMap::new();
+    let mut unsaved: HashMap<&str, usize> = HashMap::new();
+    let mut failed = plan.failed.len();
+    for job in plan.jobs.iter() {
+        progress.started(job);
+
+        match run(job, cache.as_ref(), progress) {
+            Ok(()) => {
+                // Playlists are written again on every sync, no need to check them
+                let is_playlist = matches!(job.action, Action::Playlist { .. });
+                if let (Some(root), false) = (plan.roots.get(&job.device), is_playlist) {
+                    let manifest = manifests
+                        .entry(&job.device)
+                        .or_insert_with(|| Manifest::load(root));
+                    if let Err(err) = manifest.record(root, &job.dest, &job.source, job.track) {
+                        warn!("Cannot record {}: {}", job.dest.display(), err);
+                    }
+
+                    let count = unsaved.entry(&job.device).or_default();
+                    *count += 1;
+                    if *count >= MANIFEST_EVERY {
+                        *count = 0;
+                        if let Err(err) = manifest.save(root) {
+                            warn!("Cannot save manifest of {}: {}", job.device, err);
+                        }
+                    }
+                }
+                progress.finished(job)
+            }
+            Err(err) => {
+                error!("Syncing {} failed: {}", job.dest.display(), err);
+                // Do not leave a truncated file behind, it would be skipped next time
+                let _ = std::fs::remove_file(&job.dest);
+                failed += 1;
+                progress.failed(job, &err);
+            }
+        }
+    }
+
+    // Once, rather than after every transcode
+    if let Some(cache) = cache {
+        if let Err(err) = cache.prune(&[]) {
+            warn!("Cannot prune the transcode cache: {}", err);
+        }
+    }
+
+    for (job, old) in plan.moved.iter() {
+        if !job.dest.is_file() {
+            continue;
+        }
+        debug!(
+            "Removing {}, moved to {}",
+            old.display(),
+            job.dest.display()
+        );
+        if let Err(err) = std::fs::remove_file(old) {
+            warn!("Cannot remove {}: {}", old.display(), err);
+            continue;
+        }
+        if let Some(root) = plan.roots.get(&job.device) {
+            manifests
+                .entry(&job.device)
+                .or_insert_with(|| Manifest::load(root))
+                .remove(root, old);
+        }
+        // Only goes when nothing is left in it
+        if let Some(dir) = old.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
     }
+
+    for dir in plan.resort.iter() {
+        if let Err(err) = layout::resort(dir) {
+            warn!("Cannot sort {}: {}", dir.display(), err);
+        }
+    }
+
+    for (device, manifest) in manifests.iter() {
+        if let Err(err) = manifest.save(&plan.roots[*device]) {
+            warn!("Cannot save manifest of {}: {}", device, err);
+        }
+    }
+
+    progress.done();
+
+    if failed > 0 {
+        Err(Kind::Sync)?
+    } else {
+        Ok(())
+    }
+}
+
+// Whether a picture can be copied as it is
+fn is_plain_copy(source: &Path, dest: &Path, max_size: Option<u32>) -> bool {
+    let extension = |p: &Path| p.extension().map(|e| e.to_ascii_lowercase());
+    max_size.is_none() && extension(source) == extension(dest)
+}
+
+fn run(job: &Job, cache: Option<&Cache>, progress: &mut dyn Progress) -> Result<()> {
+    if let Some(dev_parent) = job.dest.parent() {
+        debug!("Creating directories: {}", dev_parent.display());
+        std::fs::create_dir_all(dev_parent).context(Kind::Unknown)?;
+    }
+
+    match job.action {
+        Action::Copy => {
+            debug!(
+                "Copying: {} to {}",
+                job.source.display(),
+                job.dest.display()
+            );
+            std::fs::copy(&job.source, &job.dest).context(Kind::Unknown)?;
+        }
+        Action::Art { max_size } => {
+            if is_plain_copy(&job.source, &job.dest, max_size) {
+                debug!(
+                    "Copying: {} to {}",
+                    job.source.display(),
+                    job.dest.display()
+                );
+                std::fs::copy(&job.source, &job.dest).context(Kind::Unknown)?;
+            } else {
+                media::picture(&job.source, &job.dest, max_size)?;
+            }
+        }
+        Action::Playlist {
+            ref entries,
+            ref style,
+            ref root,
+        } => {
+            let entries: Vec<_> = entries
+                .iter()
+                .filter(|(_, dest)| dest.is_file())
+                .map(|(entry, dest)| (entry, dest.clone()))
+                .collect();
+
+            debug!("Writing playlist {}", job.dest.display());
+            let content = playlist::render(&entries, &job.dest, root, style);
+            std::fs::write(&job.dest, content).context(Kind::Playlist)?;
+        }
+        Action::Transcode(ref profile) => {
+            let mut advanced = |fraction| progress.advanced(job, fraction);
+
+            match cache {
+                Some(cache) => {
+                    let cached = cache.get_or_insert(&job.source, profile, &mut advanced)?;
+                    debug!("Copying: {} to {}", cached.display(), job.dest.display());
+                    std::fs::copy(&cached, &job.dest).context(Kind::Unknown)?;
+                }
+                None => media::transcode(&job.source, &job.dest, profile, &mut advanced)?,
+            }
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    // A library of `files`, along with their contents, and a device whose
+    // settings go on with `car`, both below `root`
+    fn setup(root: &Path, files: &[(&str, &str)], car: &str) -> (Config, Library) {
+        let _ = std::fs::remove_dir_all(root);
+        let (lib, dev) = (root.join("lib"), root.join("dev"));
+        for (file, content) in files {
+            write(&lib.join(file), content.as_bytes());
+        }
+        std::fs::create_dir_all(&dev).unwrap();
+
+        let conf: Config = toml::from_str(&format!(
+            r#"
+            libraries = [["Music", "{lib}"]]
+            [[devices]]
+            name = "Music"
+            location = "{lib}"
+            format = "Flac"
+            [[devices]]
+            name = "Car"
+            location = "{dev}"
+            format = "Flac"
+            {car}
+            "#,
+            lib = lib.display(),
+            dev = dev.display(),
+            car = car
+        ))
+        .unwrap();
+        let scanned = Device::get(&conf, Some("Music".to_owned())).unwrap();
+        let library = Library::from_device(&scanned[0], "Music", &lib);
+        (conf, library)
+    }
+
+    fn car(conf: &Config) -> Vec<Device> {
+        Device::get(conf, Some("Car".to_owned())).unwrap()
+    }
+
+    #[test]
+    fn files_flattened_to_the_same_place_collide() {
+        let root = std::env::temp_dir().join(format!("muco-collide-{}", std::process::id()));
+        let files = [("A/B - C/x.flac", "x"), ("A - B/C/x.flac", "y")];
+        let (conf, library) = setup(&root, &files, "layout = { max_folders = 1 }");
+
+        let plan = plan(&car(&conf), vec![library], Duplicates::All).unwrap();
+        assert_eq!(plan.jobs.len(), 1);
+        assert_eq!(plan.jobs[0].dest, root.join("dev/A - B - C/x.flac"));
+        assert_eq!(plan.collisions.len(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn files_synced_elsewhere_before_are_moved() {
+        let root = std::env::temp_dir().join(format!("muco-moved-{}", std::process::id()));
+        let files = [("A/1.flac", "1"), ("A/2.flac", "2")];
+        let (conf, library) = setup(&root, &files, "layout = { max_files = 1 }");
+        let dev = root.join("dev");
+
+        let first = plan(&car(&conf), vec![library.clone()], Duplicates::All).unwrap();
+        let mut manifest = Manifest::default();
+        for job in first.jobs.iter() {
+            write(&job.dest, b"synced");
+            manifest
+                .record(&dev, &job.dest, &job.source, job.track)
+                .unwrap();
+        }
+        manifest.save(&dev).unwrap();
+
+        // Synced again, the first place of each file is kept
+        let again = plan(&car(&conf), vec![library.clone()], Duplicates::All).unwrap();
+        assert!(again.jobs.is_empty() && again.moved.is_empty());
+        assert_eq!(again.skipped.len(), 2);
+
+        // Without the limit the files go back together, leaving their parts
+        let conf: Config =
+            toml::from_str(&toml::to_string(&conf).unwrap().replace("max_files = 1", "")).unwrap();
+        let last = plan(&car(&conf), vec![library], Duplicates::All).unwrap();
+        let mut moved: Vec<_> = last
+            .moved
+            .iter()
+            .map(|(job, old)| (old.clone(), job.dest.clone()))
+            .collect();
+        moved.sort();
+        assert_eq!(
+            moved,
+            vec![
+                (dev.join("A (1)/1.flac"), dev.join("A/1.flac")),
+                (dev.join("A (2)/2.flac"), dev.join("A/2.flac")),
+            ]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn files_which_cannot_be_encoded_fail_alone() {
+        let root = std::env::temp_dir().join(format!("muco-unable-{}", std::process::id()));
+        let files = [("A/1.flac", "1"), ("A/2.webm", "2")];
+        let speaker = format!(
+            "[[devices]]\nname = \"Speaker\"\nlocation = \"{}\"\nformat = \"Webm\"",
+            root.join("speaker").display()
+        );
+        let (conf, library) = setup(&root, &files, &speaker);
+        std::fs::create_dir_all(root.join("speaker")).unwrap();
+        let speaker = Device::get(&conf, Some("Speaker".to_owned())).unwrap();
+
+        let plan = plan(&speaker, vec![library], Duplicates::All).unwrap();
+        assert_eq!(plan.jobs.len(), 1);
+        assert_eq!(plan.jobs[0].dest, root.join("speaker/A/2.webm"));
+        assert_eq!(plan.failed.len(), 1);
+        assert_eq!(plan.failed[0].0.source, root.join("lib/A/1.flac"));
+        assert_eq!(plan.failed[0].1, Kind::Transcode);
+
+        let mut failed = Vec::new();
+        let mut events = progress::Events::new(|event| failed.push(event));
+        assert!(execute(&conf, plan, &mut events).is_err());
+        assert!(root.join("speaker/A/2.webm").is_file());
+        assert!(matches!(failed[1], progress::Event::Error { .. }));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn single_file_albums_are_cut_for_devices_which_can_encode() {
+        let root = std::env::temp_dir().join(format!("muco-cue-{}", std::process::id()));
+        // Ripped to WAV, the sheet was left as it was once encoded
+        let sheet = r#"
+            TITLE "Live"
+            FILE "Live.wav" WAVE
+              TRACK 01 AUDIO
+                TITLE "Intro"
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                TITLE "Outro"
+                INDEX 01 01:00:00
+            "#;
+        let files = [("Live/Live.webm", "webm"), ("Live/Live.cue", sheet)];
+        let speaker = format!(
+            "[[devices]]\nname = \"Speaker\"\nlocation = \"{}\"\nformat = \"Webm\"",
+            root.join("speaker").display()
+        );
+        let (conf, library) = setup(&root, &files, &speaker);
+        std::fs::create_dir_all(root.join("speaker")).unwrap();
+
+        let cut = plan(&car(&conf), vec![library.clone()], Duplicates::All).unwrap();
+        let mut tracks: Vec<_> = cut
+            .jobs
+            .iter()
+            .map(|job| (job.dest.clone(), job.source.clone(), job.track))
+            .collect();
+        tracks.sort();
+        let album = root.join("lib/Live/Live.webm");
+        assert_eq!(
+            tracks,
+            vec![
+                (
+                    root.join("dev/Live/01 - Intro.flac"),
+                    album.clone(),
+                    Some(1)
+                ),
+                (
+                    root.join("dev/Live/02 - Outro.flac"),
+                    album.clone(),
+                    Some(2)
+                ),
+            ]
+        );
+
+        // Webm cannot be encoded, the album goes once as it is
+        let speaker = Device::get(&conf, Some("Speaker".to_owned())).unwrap();
+        let whole = plan(&speaker, vec![library], Duplicates::All).unwrap();
+        assert_eq!(whole.jobs.len(), 1);
+        assert_eq!(whole.jobs[0].action, Action::Copy);
+        assert_eq!(whole.jobs[0].track, None);
+        assert_eq!(whole.jobs[0].dest, root.join("speaker/Live/Live.webm"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
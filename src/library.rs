@@ -1,19 +1,27 @@
 ///Library handling
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use failure::ResultExt;
+use log::warn;
+use std::time::SystemTime;
 
-use crate::config::Config;
+use crate::config::{Config, LibraryRules};
+use crate::device::Device;
 use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::index::{Changes, Index, IndexedFile};
+use crate::loudness::Loudness;
 use crate::media::Container;
-use crate::utils::*;
+use crate::metadata::Metadata;
+use crate::query::Query;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Library {
     name: String,
     location: PathBuf,
-    sources: HashMap<PathBuf, Container>,
+    index: Index,
+    playlists: Vec<PathBuf>,
+    rules: LibraryRules,
 }
 
 pub fn add(mut conf: Config, name: String, location: PathBuf) -> Result<()> {
@@ -50,54 +58,444 @@ impl Library {
     //     }
     // }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn location(&self) -> &PathBuf {
         &self.location
     }
 
     pub fn get(conf: &Config, name: Option<String>) -> Result<Vec<Library>> {
-        match name {
+        Ok(Library::scan(conf, name, false)?
+            .into_iter()
+            .map(|(library, _)| library)
+            .collect())
+    }
+
+    /// Brings the index of the libraries up to date and returns them along
+    /// with what changed. A `full` scan reads every file again. Libraries
+    /// which cannot be reached come out empty, their index left untouched.
+    pub fn scan(
+        conf: &Config,
+        name: Option<String>,
+        full: bool,
+    ) -> Result<Vec<(Library, Changes)>> {
+        let libraries: Vec<_> = match name {
             Some(name) => match conf.get_libraries().iter().find(|(n, _)| n.eq(&name)) {
-                Some(d) => {
-                    let (n, l) = d;
-                    let sources: Vec<_> = get_files(n, l).collect();
-                    let sources: HashMap<_, _> = sources
-                        .into_iter()
-                        .map(|(file, container, _base)| (file, container))
-                        .collect();
-
-                    Ok(vec![Library {
-                        name: n.clone(),
-                        location: l.clone(),
-                        sources,
-                    }])
-                }
+                Some(library) => vec![library],
                 None => Err(Kind::Nonexistant)?,
             },
-            None => {
-                let libraries: Vec<Library> = conf
-                    .get_libraries()
-                    .iter()
-                    .map(|(n, l)| {
-                        let sources: Vec<_> = get_files(n, l).collect();
-                        let sources: HashMap<_, _> = sources
-                            .into_iter()
-                            .map(|(file, container, _base)| (file, container))
-                            .collect();
-
-                        Library {
-                            name: n.clone(),
-                            location: l.clone(),
-                            sources,
-                        }
-                    })
-                    .collect();
-
-                Ok(libraries)
+            None => conf.get_libraries().iter().collect(),
+        };
+
+        Ok(libraries
+            .into_iter()
+            .map(|(n, l)| Library::index(n, l, conf.get_library_rules(n), full))
+            .collect())
+    }
+
+    fn index(name: &str, location: &Path, rules: LibraryRules, full: bool) -> (Library, Changes) {
+        let mut index = Index::load(name, location);
+
+        let changes = match index.refresh(full) {
+            Ok(changes) => {
+                if let Err(err) = index.save(name) {
+                    warn!("Cannot save index of library {}: {}", name, err);
+                }
+                changes
             }
+            Err(err) => {
+                warn!("Cannot scan library {}: {}", name, err);
+                return (
+                    Library {
+                        name: name.to_owned(),
+                        location: location.to_path_buf(),
+                        index: Index::new(location),
+                        playlists: Vec::new(),
+                        rules,
+                    },
+                    Changes::default(),
+                );
+            }
+        };
+
+        let library = Library {
+            name: name.to_owned(),
+            location: location.to_path_buf(),
+            playlists: index.playlists(),
+            index,
+            rules,
+        };
+        (library, changes)
+    }
+
+    /// The files a device has below `location`, as a library called `name`.
+    /// Its playlists, art and companions come along with the tracks.
+    pub fn from_device(dev: &Device, name: &str, location: &Path) -> Library {
+        let mut index = Index::new(location);
+        if let Err(err) = index.refresh(false) {
+            warn!("Cannot scan {} of {}: {}", name, dev.name(), err);
+        }
+        index
+            .files_mut()
+            .retain(|path, _| dev.source().contains_key(path));
+
+        Library {
+            name: name.to_owned(),
+            location: location.to_path_buf(),
+            playlists: index.playlists(),
+            index,
+            rules: LibraryRules::default(),
         }
     }
 
+    /// Adds a file found after the scan, it gets indexed on the next one
+    pub(crate) fn insert(&mut self, path: PathBuf, container: Container) {
+        let (size, mtime) = path
+            .metadata()
+            .and_then(|m| Ok((m.len(), m.modified()?)))
+            .unwrap_or((0, SystemTime::UNIX_EPOCH));
+        let file = IndexedFile::read(&path, container, size, mtime);
+        self.index.files_mut().insert(path, file);
+    }
+
+    pub(crate) fn remove(&mut self, path: &PathBuf) -> Option<Container> {
+        self.index.files_mut().remove(path).map(|f| f.container)
+    }
+
+    pub fn playlists(&self) -> &[PathBuf] {
+        &self.playlists
+    }
+
+    /// Pictures found in the library, sorted
+    pub fn images(&self) -> Vec<PathBuf> {
+        let is_image = |p: &PathBuf| match p.extension().and_then(|e| e.to_str()) {
+            Some(extension) => ["jpg", "jpeg", "png"]
+                .iter()
+                .any(|e| extension.eq_ignore_ascii_case(e)),
+            None => false,
+        };
+        self.index.others().into_iter().filter(is_image).collect()
+    }
+
+    /// Files neither audio nor playlists, sorted
+    pub fn others(&self) -> Vec<PathBuf> {
+        self.index.others()
+    }
+
+    /// Files matching `query` along with their metadata, sorted by path.
+    /// Paths are matched relative to the library.
+    pub fn query(&self, query: &Query) -> Vec<(PathBuf, Metadata)> {
+        let mut found: Vec<_> = self
+            .files()
+            .iter()
+            .filter_map(|(path, file)| {
+                let relative = path.strip_prefix(&self.location).unwrap_or(path);
+                if query.matches(relative, &file.tags) {
+                    Some((path.clone(), file.tags.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+
+    /// Indexed files of the library
+    pub fn files(&self) -> &HashMap<PathBuf, IndexedFile> {
+        self.index.files()
+    }
+
     pub fn source(self) -> HashMap<PathBuf, Container> {
-        self.sources
+        self.index
+            .files()
+            .iter()
+            .map(|(path, file)| (path.clone(), file.container))
+            .collect()
+    }
+
+    /// Files of a track left out for a better one, along with that one, when
+    /// the library picks the best source of each track. Files are of the same
+    /// track when they only differ by their extension, or when they have the
+    /// same artist, album, disc, track number and title.
+    pub fn superseded(&self) -> Vec<(PathBuf, PathBuf)> {
+        if !self.rules.best_source {
+            return Vec::new();
+        }
+
+        let mut files: Vec<_> = self.files().iter().collect();
+        files.sort_by(|a, b| a.0.cmp(b.0));
+
+        // Files are grouped by any of their keys, groups sharing a key merge
+        let mut group_of: HashMap<String, usize> = HashMap::new();
+        let mut groups: Vec<usize> = Vec::new();
+        let find = |groups: &Vec<usize>, mut g: usize| {
+            while groups[g] != g {
+                g = groups[g];
+            }
+            g
+        };
+
+        for (path, file) in files.iter() {
+            let g = groups.len();
+            groups.push(g);
+
+            for key in track_keys(path, file) {
+                match group_of.get(&key) {
+                    Some(&other) => {
+                        let (a, b) = (find(&groups, other), find(&groups, g));
+                        groups[b] = a;
+                    }
+                    None => {
+                        group_of.insert(key, g);
+                    }
+                }
+            }
+        }
+
+        let mut tracks: HashMap<usize, Vec<(&PathBuf, &IndexedFile)>> = HashMap::new();
+        for (g, file) in files.into_iter().enumerate() {
+            tracks.entry(find(&groups, g)).or_default().push(file);
+        }
+
+        let mut superseded = Vec::new();
+        for (_, mut track) in tracks.into_iter().filter(|(_, t)| t.len() > 1) {
+            // The best first, the first path when they are as good
+            track.sort_by(|a, b| self.quality(b.1).cmp(&self.quality(a.1)).then(a.0.cmp(b.0)));
+            for (path, _) in track[1..].iter() {
+                superseded.push(((*path).clone(), track[0].0.clone()));
+            }
+        }
+
+        superseded.sort();
+        superseded
+    }
+
+    // Higher is better
+    fn quality(&self, file: &IndexedFile) -> (usize, u8, u32, u32) {
+        let prefer = &self.rules.prefer;
+        let rank = prefer
+            .iter()
+            .position(|c| *c == file.container)
+            .unwrap_or(prefer.len());
+
+        (
+            prefer.len() - rank,
+            file.tags.bits_per_sample.unwrap_or_default(),
+            file.tags.bitrate.unwrap_or_default(),
+            file.tags.sample_rate.unwrap_or_default(),
+        )
+    }
+
+    /// Hashes the audio of the files which were not hashed yet, keeping the
+    /// hashes in the index. Files which cannot be read are left out.
+    pub fn hash(&mut self) -> HashMap<PathBuf, String> {
+        let mut hashed = false;
+        let mut hashes = HashMap::new();
+
+        for (path, file) in self.index.files_mut().iter_mut() {
+            hashed |= file.hash.is_none();
+            match file.hash(path) {
+                Ok(hash) => {
+                    hashes.insert(path.clone(), hash.to_owned());
+                }
+                Err(err) => warn!("Cannot hash {}: {}", path.display(), err),
+            }
+        }
+
+        if hashed {
+            if let Err(err) = self.index.save(&self.name) {
+                warn!("Cannot save index of library {}: {}", self.name, err);
+            }
+        }
+        hashes
+    }
+
+    /// Measures the loudness of the files not measured yet, returning how
+    /// many were. `measuring` is called before each file.
+    pub fn analyze(&mut self, measuring: &mut dyn FnMut(&Path)) -> usize {
+        let mut measured = 0;
+        let mut paths: Vec<_> = self
+            .index
+            .files()
+            .iter()
+            .filter(|(_, file)| file.loudness.is_none())
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            measuring(&path);
+            match Loudness::analyze(&path) {
+                Ok(loudness) => {
+                    if let Some(file) = self.index.files_mut().get_mut(&path) {
+                        file.loudness = Some(loudness);
+                        measured += 1;
+                    }
+                }
+                Err(err) => warn!("Cannot measure {}: {}", path.display(), err),
+            }
+        }
+
+        if measured > 0 {
+            if let Err(err) = self.index.save(&self.name) {
+                warn!("Cannot save index of library {}: {}", self.name, err);
+            }
+        }
+        measured
+    }
+}
+
+// What makes two files the same track
+fn track_keys(path: &Path, file: &IndexedFile) -> Vec<String> {
+    let mut keys = vec![format!("path:{}", path.with_extension("").display())];
+
+    let tag = |name| file.tags.get(name).map(|v| v.to_lowercase());
+    // "3/12" and "03" are the same track, a missing disc the first
+    let number = |name| {
+        let value = tag(name)?;
+        let number = value.split('/').next()?.trim().parse::<u32>().ok()?;
+        Some(number.to_string())
+    };
+    let artist = tag("albumartist").or_else(|| tag("artist"));
+    let disc = number("discnumber").unwrap_or_else(|| "1".to_owned());
+    let track = number("tracknumber").unwrap_or_default();
+    if let (Some(artist), Some(album), Some(title)) = (artist, tag("album"), tag("title")) {
+        keys.push(format!(
+            "tags:{}\0{}\0{}\0{}\0{}",
+            artist, album, disc, track, title
+        ));
+    }
+    keys
+}
+
+/// Groups the files with the same audio within and across libraries. Files
+/// in a group come in the order of the libraries, then of their paths, the
+/// first one being the copy to keep.
+pub fn duplicates(libraries: &mut [Library]) -> Vec<Vec<PathBuf>> {
+    let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut order = Vec::new();
+
+    for library in libraries.iter_mut() {
+        let mut hashes: Vec<_> = library.hash().into_iter().collect();
+        hashes.sort();
+
+        for (path, hash) in hashes {
+            let group = groups.entry(hash.clone()).or_insert_with(|| {
+                order.push(hash);
+                Vec::new()
+            });
+            group.push(path);
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|hash| groups.remove(&hash))
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    // A library of files with their tags, picking the best source
+    fn library(files: &[(&str, &[(&str, &str)])]) -> Library {
+        let location = Path::new("/music");
+        let mut index = Index::new(location);
+        for (path, tags) in files {
+            let path = location.join(path);
+            let container = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default();
+            let mut file = IndexedFile {
+                container: Container::try_from(container).unwrap(),
+                size: 0,
+                mtime: SystemTime::UNIX_EPOCH,
+                tags: Metadata::default(),
+                hash: None,
+                loudness: None,
+            };
+            for (tag, value) in tags.iter() {
+                file.tags.tags.insert(tag.to_string(), value.to_string());
+            }
+            index.files_mut().insert(path, file);
+        }
+
+        Library {
+            name: "Music".to_owned(),
+            location: location.to_path_buf(),
+            index,
+            playlists: Vec::new(),
+            rules: LibraryRules {
+                best_source: true,
+                ..LibraryRules::default()
+            },
+        }
+    }
+
+    const ALBUM: [(&str, &str); 3] = [("artist", "A"), ("album", "B"), ("title", "Intro")];
+
+    fn superseded(library: &Library) -> Vec<(String, String)> {
+        let name = |p: &PathBuf| p.strip_prefix("/music").unwrap().display().to_string();
+        library
+            .superseded()
+            .iter()
+            .map(|(left, kept)| (name(left), name(kept)))
+            .collect()
+    }
+
+    #[test]
+    fn copies_of_a_track_are_superseded() {
+        let tagged = |numbers: &[(&'static str, &'static str)]| {
+            ALBUM.iter().chain(numbers).cloned().collect::<Vec<_>>()
+        };
+        // Numbered the way each format tends to be
+        let mp3 = tagged(&[("tracknumber", "3/12")]);
+        let flac = tagged(&[("discnumber", "1/1"), ("tracknumber", "03")]);
+        let library = library(&[
+            ("mp3/03 Intro.mp3", &mp3),
+            ("flac/03 Intro.flac", &flac),
+            ("Other/x.mp3", &[]),
+            ("Other/x.flac", &[]),
+        ]);
+
+        assert_eq!(
+            superseded(&library),
+            vec![
+                ("Other/x.mp3".to_owned(), "Other/x.flac".to_owned()),
+                (
+                    "mp3/03 Intro.mp3".to_owned(),
+                    "flac/03 Intro.flac".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_of_the_same_title_are_kept() {
+        let tagged = |disc: &'static str, track: &'static str| {
+            let numbers = [("discnumber", disc), ("tracknumber", track)];
+            ALBUM
+                .iter()
+                .chain(numbers.iter())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        // An intro on each disc, and a reprise on the first
+        let (first, reprise, second) = (tagged("1", "1"), tagged("1", "9"), tagged("2/2", "1"));
+        let library = library(&[
+            ("B/1-01 Intro.flac", &first),
+            ("B/1-09 Intro.mp3", &reprise),
+            ("B/2-01 Intro.mp3", &second),
+        ]);
+
+        assert_eq!(superseded(&library), Vec::new());
     }
 }
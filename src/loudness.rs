@@ -0,0 +1,256 @@
+/// EBU R128 loudness of library files, and the gain bringing them to a
+/// common loudness on devices
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use failure::ResultExt;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::config::GainConf;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// True peak in dBTP
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Measures a file with the ebur128 filter of ffmpeg
+    pub fn analyze(path: &Path) -> Result<Loudness> {
+        debug!("Measuring loudness of {}", path.display());
+        let output = Command::new("ffmpeg")
+            .arg("-nostdin")
+            .arg("-hide_banner")
+            .arg("-nostats")
+            .arg("-i")
+            .arg(path)
+            .arg("-map")
+            .arg("0:a:0")
+            .arg("-af")
+            .arg("ebur128=peak=true")
+            .arg("-f")
+            .arg("null")
+            .arg("/dev/null")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .context(Kind::Loudness)?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match (output.status.success(), parse_summary(&stderr)) {
+            (true, Some(loudness)) => Ok(loudness),
+            _ => {
+                error!(
+                    "Cannot measure loudness of {}: {}",
+                    path.display(),
+                    stderr.lines().last().unwrap_or_default().trim()
+                );
+                Err(Kind::Loudness)?
+            }
+        }
+    }
+
+    /// Gain in dB bringing the file to `target` LUFS, None for silence
+    pub fn gain(&self, target: f64) -> Option<f64> {
+        Some(target - self.integrated).filter(|g| g.is_finite())
+    }
+
+    /// ffmpeg output options writing ReplayGain tags
+    pub fn tag_args(&self, conf: &GainConf) -> Vec<String> {
+        let gain = match self.gain(conf.target) {
+            Some(gain) => gain,
+            None => return Vec::new(),
+        };
+
+        vec![
+            "-metadata".to_owned(),
+            format!("REPLAYGAIN_TRACK_GAIN={:.2} dB", gain),
+            "-metadata".to_owned(),
+            format!(
+                "REPLAYGAIN_TRACK_PEAK={:.6}",
+                10f64.powf(self.true_peak / 20.0)
+            ),
+        ]
+    }
+
+    /// ffmpeg output options applying the gain to the audio. When the gain
+    /// pushes the true peak over the ceiling, a limiter runs on the signal
+    /// oversampled 4 times, the way true peaks are measured.
+    pub fn filter_args(&self, conf: &GainConf, sample_rate: Option<u32>) -> Vec<String> {
+        let gain = match self.gain(conf.target) {
+            Some(gain) => gain,
+            None => return Vec::new(),
+        };
+
+        let mut filter = format!("volume={:.2}dB", gain);
+        if self.true_peak + gain > conf.ceiling {
+            // alimiter does not go below -24 dB
+            let limit = 10f64.powf(conf.ceiling / 20.0).clamp(0.0625, 1.0);
+            let limiter = format!("alimiter=limit={:.6}:level=0", limit);
+            match sample_rate {
+                Some(rate) => filter.push_str(&format!(
+                    ",aresample={},{},aresample={}",
+                    rate * 4,
+                    limiter,
+                    rate
+                )),
+                None => filter.push_str(&format!(",{}", limiter)),
+            }
+        }
+
+        vec!["-af".to_owned(), filter]
+    }
+}
+
+// Reads the summary printed at the end, made of lines like
+// "    I:         -14.2 LUFS" and "    Peak:        0.3 dBFS"
+fn parse_summary(stderr: &str) -> Option<Loudness> {
+    let summary = &stderr[stderr.rfind("Summary:")?..];
+    let value = |key: &str| {
+        summary
+            .lines()
+            .map(str::trim)
+            .find_map(|l| l.strip_prefix(key))?
+            .split_whitespace()
+            .next()?
+            .parse::<f64>()
+            .ok()
+    };
+
+    Some(Loudness {
+        integrated: value("I:")?,
+        true_peak: value("Peak:")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As printed by ffmpeg 6, after a line per 100 ms of audio
+    const OUTPUT: &str = "\
+[Parsed_ebur128_0 @ 0x5581c0a3c2c0] t: 0.1       TARGET:-23 LUFS    M:-120.7 S:-120.7     I: -70.0 LUFS       LRA:   0.0 LU  FTPK: -inf dBFS  TPK: -inf dBFS
+[Parsed_ebur128_0 @ 0x5581c0a3c2c0] t: 0.2       TARGET:-23 LUFS    M: -16.1 S:-120.7     I: -16.1 LUFS       LRA:   0.0 LU  FTPK: -1.2 dBFS  TPK: -1.2 dBFS
+[out#0/null @ 0x5581c0a3b000] video:0kB audio:41344kB subtitle:0kB other streams:0kB global headers:0kB muxing overhead: unknown
+size=N/A time=00:04:05.20 bitrate=N/A speed= 612x
+[Parsed_ebur128_0 @ 0x5581c0a3c2c0] Summary:
+
+  Integrated loudness:
+    I:         -14.2 LUFS
+    Threshold: -24.6 LUFS
+
+  Loudness range:
+    LRA:         6.1 LU
+    Threshold: -34.5 LUFS
+    LRA low:   -19.1 LUFS
+    LRA high:  -13.0 LUFS
+
+  True peak:
+    Peak:        0.3 dBFS
+";
+
+    #[test]
+    fn the_summary_is_read() {
+        assert_eq!(
+            parse_summary(OUTPUT),
+            Some(Loudness {
+                integrated: -14.2,
+                true_peak: 0.3,
+            })
+        );
+
+        let silence = OUTPUT
+            .replace("-14.2 LUFS", "-70.0 LUFS")
+            .replace("0.3 dBFS", "-inf dBFS");
+        let silence = parse_summary(&silence).unwrap();
+        assert_eq!(silence.true_peak, f64::NEG_INFINITY);
+
+        // Only the lines of the summary count
+        assert_eq!(
+            parse_summary(&OUTPUT[..OUTPUT.find("[out#0").unwrap()]),
+            None
+        );
+        assert_eq!(
+            parse_summary(&OUTPUT[..OUTPUT.find("  True peak").unwrap()]),
+            None
+        );
+    }
+
+    #[test]
+    fn gains_within_the_ceiling_are_applied_alone() {
+        let conf = GainConf::default();
+        let quiet = Loudness {
+            integrated: -24.0,
+            true_peak: -8.0,
+        };
+        assert_eq!(
+            quiet.filter_args(&conf, Some(44100)),
+            ["-af", "volume=6.00dB"]
+        );
+
+        let loud = Loudness {
+            integrated: -10.0,
+            true_peak: 0.5,
+        };
+        assert_eq!(
+            loud.filter_args(&conf, Some(44100)),
+            ["-af", "volume=-8.00dB"]
+        );
+    }
+
+    #[test]
+    fn peaks_over_the_ceiling_are_limited_oversampled() {
+        let conf = GainConf::default();
+        let peaky = Loudness {
+            integrated: -24.0,
+            true_peak: -3.0,
+        };
+        let limiter = "alimiter=limit=0.891251:level=0";
+
+        assert_eq!(
+            peaky.filter_args(&conf, Some(44100)),
+            [
+                "-af".to_owned(),
+                format!("volume=6.00dB,aresample=176400,{},aresample=44100", limiter)
+            ]
+        );
+        assert_eq!(
+            peaky.filter_args(&conf, Some(96000)),
+            [
+                "-af".to_owned(),
+                format!("volume=6.00dB,aresample=384000,{},aresample=96000", limiter)
+            ]
+        );
+        // Without a known rate there is nothing to go back to
+        assert_eq!(
+            peaky.filter_args(&conf, None),
+            ["-af".to_owned(), format!("volume=6.00dB,{}", limiter)]
+        );
+
+        // As low as alimiter goes
+        let conf = GainConf {
+            ceiling: -30.0,
+            ..conf
+        };
+        assert_eq!(
+            peaky.filter_args(&conf, None),
+            ["-af", "volume=6.00dB,alimiter=limit=0.062500:level=0"]
+        );
+    }
+
+    #[test]
+    fn silence_gets_no_gain() {
+        let silence = Loudness {
+            integrated: f64::NEG_INFINITY,
+            true_peak: f64::NEG_INFINITY,
+        };
+        let conf = GainConf::default();
+        assert!(silence.filter_args(&conf, Some(44100)).is_empty());
+        assert!(silence.tag_args(&conf).is_empty());
+    }
+}
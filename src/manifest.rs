@@ -0,0 +1,109 @@
+/// Record of the files written to a device
+///
+/// Every file a sync writes is recorded in `.muco/manifest.json` on the
+/// device with its size and hash, for `muco verify` to find out which files
+/// got corrupted since.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::utils::hex;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub size: u64,
+    /// SHA-256 of the whole file
+    pub hash: String,
+    /// Library file it was synced from
+    pub source: PathBuf,
+    /// Number of the track cut out of the source, a single file album
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// By path relative to the device root
+    files: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    fn path(root: &Path) -> PathBuf {
+        root.join(".muco").join("manifest.json")
+    }
+
+    /// Loads the manifest of the device at `root`, an empty one if there is none
+    pub fn load(root: &Path) -> Manifest {
+        let file = match File::open(Self::path(root)) {
+            Ok(file) => file,
+            Err(_) => return Manifest::default(),
+        };
+
+        serde_json::from_reader(io::BufReader::new(file)).unwrap_or_else(|err| {
+            warn!("Cannot read manifest of {}: {}", root.display(), err);
+            Manifest::default()
+        })
+    }
+
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let path = Self::path(root);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(Kind::Manifest)?;
+        }
+
+        let partial = path.with_extension("part");
+        let file = File::create(&partial).context(Kind::Manifest)?;
+        serde_json::to_writer(io::BufWriter::new(file), self).context(Kind::Manifest)?;
+        std::fs::rename(&partial, &path).context(Kind::Manifest)?;
+        Ok(())
+    }
+
+    /// Records `dest` as it is now, synced from `source` or from one of its
+    /// tracks
+    pub fn record(
+        &mut self,
+        root: &Path,
+        dest: &Path,
+        source: &Path,
+        track: Option<u32>,
+    ) -> Result<()> {
+        let relative = dest.strip_prefix(root).context(Kind::Manifest)?;
+        let record = Record {
+            size: dest.metadata().context(Kind::Manifest)?.len(),
+            hash: hash(dest)?,
+            source: source.to_path_buf(),
+            track,
+        };
+
+        self.files.insert(relative.to_path_buf(), record);
+        Ok(())
+    }
+
+    /// Forgets `dest`, once removed from the device
+    pub fn remove(&mut self, root: &Path, dest: &Path) -> Option<Record> {
+        self.files.remove(dest.strip_prefix(root).ok()?)
+    }
+
+    pub fn get(&self, relative: &Path) -> Option<&Record> {
+        self.files.get(relative)
+    }
+
+    /// Recorded files, by path relative to the device root
+    pub fn files(&self) -> impl Iterator<Item = (&PathBuf, &Record)> {
+        self.files.iter()
+    }
+}
+
+pub fn hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path).context(Kind::Manifest)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).context(Kind::Manifest)?;
+    Ok(hex(&hasher.result()))
+}
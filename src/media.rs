@@ -1,12 +1,20 @@
 /// Container and media handling
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
-use std::convert::Into;
 use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
 
+use crate::config::{Capabilities, Encoder};
 use crate::error::{MucoError, MucoErrorKind as Kind, MucoResult as Result};
+use crate::metadata::{self, Metadata};
+use failure::ResultExt;
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Container {
     Webm,
     Flac,
@@ -32,9 +40,9 @@ impl TryFrom<&str> for Container {
     }
 }
 
-impl Into<&'static str> for Container {
-    fn into(self) -> &'static str {
-        match self {
+impl From<Container> for &'static str {
+    fn from(container: Container) -> &'static str {
+        match container {
             Container::Webm => "webm",
             Container::Flac => "flac",
             Container::Mp3 => "mp3",
@@ -42,8 +50,583 @@ impl Into<&'static str> for Container {
     }
 }
 
-pub trait Transcode {
-    /// transcode() must copy the transcoded file to the cache
-    /// and update the source Library
-    fn transcode(&self, source: Container, target: Container) -> Result<()>;
+/// Encoder settings used to produce a container
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Profile {
+    format: Container,
+    args: Vec<String>,
+}
+
+impl Profile {
+    /// Whether files can be transcoded to `format`
+    pub fn supports(format: Container) -> bool {
+        format != Container::Webm
+    }
+
+    pub fn new(format: Container, encoder: &Encoder) -> Result<Profile> {
+        let mut args: Vec<String> = match format {
+            Container::Mp3 => vec!["-vn".into(), "-codec:a".into(), "libmp3lame".into()],
+            Container::Flac => vec!["-acodec".into(), "flac".into()],
+            _ => {
+                error!("Transcoding not supported to {:?}", format);
+                Err(Kind::Transcode)?
+            }
+        };
+        if format == Container::Mp3 {
+            match encoder.quality {
+                Some(quality) => args.extend(vec!["-q:a".into(), quality.min(9).to_string()]),
+                None => args.extend(vec![
+                    "-b:a".into(),
+                    format!("{}k", encoder.bitrate.unwrap_or(320)),
+                ]),
+            }
+        }
+
+        Ok(Profile { format, args })
+    }
+
+    /// Rewrites the container without touching the audio, for options like
+    /// tags to apply to a file already in the right format
+    pub fn copy(format: Container) -> Profile {
+        Profile {
+            format,
+            args: vec!["-codec".to_owned(), "copy".to_owned()],
+        }
+    }
+
+    /// Adds output options, e.g. filters or tags for a given file
+    pub fn with_args(mut self, args: Vec<String>) -> Profile {
+        self.args.extend(args);
+        self
+    }
+
+    /// Leaves out the art embedded in the source
+    pub fn without_art(mut self) -> Profile {
+        if !self.args.iter().any(|a| a == "-vn") {
+            self.args.push("-vn".to_owned());
+        }
+        self
+    }
+
+    /// Leaves out the header MP3 players need to play tracks without gaps
+    pub fn without_gapless(mut self) -> Profile {
+        if self.is_gapless() {
+            self.args.push("-write_xing".to_owned());
+            self.args.push("0".to_owned());
+        }
+        self
+    }
+
+    // Whether the output gets an MP3 header with encoder delay and padding
+    fn is_gapless(&self) -> bool {
+        self.format == Container::Mp3 && !self.args.windows(2).any(|a| a == ["-write_xing", "0"])
+    }
+
+    // Whether the audio is left as it is
+    fn is_copy(&self) -> bool {
+        self.args.windows(2).any(|a| a == ["-codec", "copy"])
+    }
+
+    /// The ffmpeg encoder writing the audio, none when it is copied
+    pub fn encoder(&self) -> Option<&str> {
+        self.args
+            .windows(2)
+            .find(|a| a[0] == "-acodec" || a[0] == "-codec:a")
+            .map(|a| a[1].as_str())
+            .filter(|e| *e != "copy")
+    }
+
+    pub fn format(&self) -> Container {
+        self.format
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Rough size of the output for a source of `size` bytes lasting
+    /// `duration` seconds
+    pub fn estimate(&self, size: u64, duration: Option<f64>) -> u64 {
+        let value = |name: &str| {
+            self.args
+                .windows(2)
+                .find(|a| a[0] == name)
+                .and_then(|a| a[1].trim_end_matches('k').parse::<usize>().ok())
+        };
+        // Average bitrates of the variable bitrate qualities of LAME
+        const QUALITIES: [f64; 10] = [245., 225., 190., 175., 165., 130., 115., 100., 85., 65.];
+        let kbps = match (value("-b:a"), value("-q:a")) {
+            (Some(bitrate), _) => bitrate as f64,
+            (None, Some(quality)) => QUALITIES[quality.min(9)],
+            (None, None) => 320.0,
+        };
+
+        match (self.format, duration) {
+            (Container::Mp3, Some(duration)) => (duration * kbps * 125.0) as u64,
+            _ => size,
+        }
+    }
+
+    /// Identifies the output of the encoder, two profiles with the same id
+    /// produce the same file from the same source.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.format, self.args.join(" "))
+    }
+}
+
+// Rates files are resampled to
+const SAMPLE_RATES: [u32; 11] = [
+    8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+/// Output options bringing a source with the stream properties of `meta`
+/// within the capabilities of a device, none when it already is
+pub fn fit(caps: &Capabilities, meta: &Metadata, format: Container) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if let (Some(max), Some(rate)) = (caps.max_sample_rate, meta.sample_rate) {
+        if rate > max {
+            args.push("-ar".to_owned());
+            args.push(nearest_rate(rate, max).to_string());
+        }
+    }
+
+    let channels = if caps.mono {
+        Some(1)
+    } else {
+        caps.max_channels
+    };
+    if let (Some(max), Some(found)) = (channels, meta.channels) {
+        if found > max {
+            args.push("-ac".to_owned());
+            args.push(max.to_string());
+        }
+    }
+
+    // Lossy formats have no bit depth
+    if format == Container::Flac {
+        if let (Some(max), Some(bits)) = (caps.max_bit_depth, meta.bits_per_sample) {
+            if bits > max {
+                let sample_fmt = if max <= 16 { "s16" } else { "s32" };
+                args.extend(
+                    ["-sample_fmt", sample_fmt, "-bits_per_raw_sample"]
+                        .iter()
+                        .map(|a| (*a).to_owned()),
+                );
+                args.push(max.min(24).to_string());
+            }
+        }
+    }
+
+    args
+}
+
+// Highest rate not above `max`, or the one of the same family as `rate`
+// right below it, 88.2 kHz going to 44.1 kHz rather than 48 kHz. Rates
+// further down are never picked for the family, 96 kHz going to 44.1 kHz
+// rather than 32 kHz.
+fn nearest_rate(rate: u32, max: u32) -> u32 {
+    let family = |r: u32| r.is_multiple_of(11025);
+    let below = SAMPLE_RATES.iter().copied().filter(|r| *r <= max);
+    let highest = match below.clone().max() {
+        Some(highest) => highest,
+        None => return max,
+    };
+    below
+        .filter(|r| family(*r) == family(rate) && f64::from(*r) * 1.1 >= f64::from(highest))
+        .max()
+        .unwrap_or(highest)
+}
+
+/// Whether ffmpeg can be run, looked up once
+pub fn has_ffmpeg() -> bool {
+    static FOUND: OnceLock<bool> = OnceLock::new();
+    *FOUND.get_or_init(|| {
+        let found = Command::new("ffmpeg")
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        debug!("ffmpeg found: {}", found);
+        found
+    })
+}
+
+/// Encoders ffmpeg was built with, like `libmp3lame`, none when ffmpeg is
+/// not there or does not tell. Looked up once.
+pub fn encoders() -> Option<&'static BTreeSet<String>> {
+    static ENCODERS: OnceLock<Option<BTreeSet<String>>> = OnceLock::new();
+    ENCODERS
+        .get_or_init(|| {
+            let output = Command::new("ffmpeg")
+                .arg("-hide_banner")
+                .arg("-encoders")
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()?;
+
+            // Lines like " A....D libmp3lame  libmp3lame MP3 (MPEG audio layer 3)",
+            // after a legend ending in a line of dashes
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let encoders: BTreeSet<String> = stdout
+                .lines()
+                .skip_while(|l| !l.trim_start().starts_with("---"))
+                .skip(1)
+                .filter_map(|l| l.split_whitespace().nth(1))
+                .map(|e| e.to_owned())
+                .collect();
+            Some(encoders).filter(|e| !e.is_empty())
+        })
+        .as_ref()
+}
+
+/// Fails with a clear error when ffmpeg, needed to `what`, is not there
+pub fn require_ffmpeg(what: &str) -> Result<()> {
+    if has_ffmpeg() {
+        Ok(())
+    } else {
+        error!("ffmpeg is needed to {} but was not found in PATH", what);
+        Err(Kind::NoFfmpeg)?
+    }
+}
+
+/// Whether `profile` can be transcoded with what is at hand
+pub fn can_transcode(profile: &Profile) -> bool {
+    ffmpeg_encodes(profile) || native(profile)
+}
+
+fn ffmpeg_encodes(profile: &Profile) -> bool {
+    has_ffmpeg() && lists_encoder(profile, encoders())
+}
+
+/// Whether an ffmpeg listing `encoders` has the encoder of `profile`. When
+/// ffmpeg does not list its encoders, it is trusted to have them.
+pub(crate) fn lists_encoder(profile: &Profile, encoders: Option<&BTreeSet<String>>) -> bool {
+    match (profile.encoder(), encoders) {
+        (Some(encoder), Some(encoders)) => encoders.contains(encoder),
+        _ => true,
+    }
+}
+
+/// Whether `profile` can be transcoded without ffmpeg
+#[cfg(feature = "native")]
+pub(crate) fn native(profile: &Profile) -> bool {
+    crate::native::supports(profile)
+}
+
+#[cfg(not(feature = "native"))]
+pub(crate) fn native(_: &Profile) -> bool {
+    false
+}
+
+/// Transcode `source` into `dest` using ffmpeg, or natively when ffmpeg is
+/// not there and muco was built with the `native` feature. `progress` gets
+/// called with the fraction of the source transcoded so far.
+pub fn transcode(
+    source: &Path,
+    dest: &Path,
+    profile: &Profile,
+    progress: &mut dyn FnMut(f64),
+) -> Result<()> {
+    if !ffmpeg_encodes(profile) && native(profile) {
+        #[cfg(feature = "native")]
+        return crate::native::transcode(source, dest, profile, progress);
+    }
+    require_ffmpeg(&format!("transcode {}", source.display()))?;
+
+    debug!("Transcoding {} to {}", source.display(), dest.display());
+    let mut child = Command::new("ffmpeg")
+        .current_dir(".")
+        .arg("-nostdin")
+        .arg("-y")
+        .arg("-nostats")
+        .arg("-progress")
+        .arg("pipe:2")
+        .arg("-i")
+        .arg(source)
+        .args(profile.args())
+        .arg(dest)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(Kind::Transcode)?;
+
+    let mut duration = None;
+    let mut last_line = String::new();
+
+    if let Some(stderr) = child.stderr.take() {
+        for line in BufReader::new(stderr).lines() {
+            let line = line.context(Kind::Transcode)?;
+
+            if let Some(total) = parse_duration(&line) {
+                duration.get_or_insert(total);
+            } else if let Some((key, value)) = line.split_once('=') {
+                // Older ffmpeg releases call it out_time_ms, although it is in microseconds too
+                if key == "out_time_us" || key == "out_time_ms" {
+                    if let (Some(total), Ok(us)) = (duration, value.parse::<f64>()) {
+                        progress((us / 1_000_000.0 / total).clamp(0.0, 1.0));
+                    }
+                }
+            } else if !line.trim().is_empty() {
+                last_line = line;
+            }
+        }
+    }
+
+    let status = child.wait().context(Kind::Transcode)?;
+    if status.success() {
+        if profile.is_gapless() {
+            keep_gapless(source, dest, profile)?;
+        }
+        progress(1.0);
+        Ok(())
+    } else {
+        error!(
+            "ffmpeg failed on {}: {}",
+            source.display(),
+            last_line.trim()
+        );
+        Err(Kind::Transcode)?
+    }
+}
+
+// ffmpeg copying MP3 audio writes a header of its own, without the encoder
+// delay and padding of the source, which are put back. Encoded files are
+// checked to have them.
+fn keep_gapless(source: &Path, dest: &Path, profile: &Profile) -> Result<()> {
+    if profile.is_copy() {
+        if let Some(gapless) = metadata::read_gapless(source)? {
+            debug!(
+                "Encoder delay {} and padding {} of {}",
+                gapless.delay,
+                gapless.padding,
+                source.display()
+            );
+            metadata::write_gapless(dest, gapless)?;
+        }
+    } else if metadata::read_gapless(dest)?.is_none() {
+        warn!(
+            "Encoding {} left out the encoder delay and padding, it will not play gapless",
+            source.display()
+        );
+    }
+    Ok(())
+}
+
+/// Writes the picture of `source`, an image or a track with embedded art, to
+/// `dest` in the format its extension calls for. Pictures larger than
+/// `max_size` pixels are scaled down, JPEGs come out baseline.
+pub fn picture(source: &Path, dest: &Path, max_size: Option<u32>) -> Result<()> {
+    require_ffmpeg("write album art")?;
+    debug!(
+        "Writing picture of {} to {}",
+        source.display(),
+        dest.display()
+    );
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-nostdin")
+        .arg("-y")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(source)
+        .arg("-map")
+        .arg("0:v:0")
+        .arg("-frames:v")
+        .arg("1");
+    if let Some(max) = max_size {
+        command.arg("-vf").arg(format!(
+            "scale=w='min({0},iw)':h='min({0},ih)':force_original_aspect_ratio=decrease",
+            max
+        ));
+    }
+
+    let output = command
+        .arg("-q:v")
+        .arg("2")
+        .arg(dest)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context(Kind::Transcode)?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        error!(
+            "ffmpeg failed on {}: {}",
+            source.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(Kind::Transcode)?
+    }
+}
+
+/// Decodes a whole file with ffmpeg, or natively with the `native` feature,
+/// returning the first error it ran into, if any. Fails when neither can be
+/// done.
+pub fn decode(path: &Path) -> Result<Option<String>> {
+    #[cfg(feature = "native")]
+    {
+        if !has_ffmpeg() {
+            return crate::native::decode(path);
+        }
+    }
+    require_ffmpeg("decode files")?;
+
+    debug!("Decoding {}", path.display());
+    let output = Command::new("ffmpeg")
+        .arg("-nostdin")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-f")
+        .arg("null")
+        .arg("/dev/null")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context(Kind::Transcode)?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let first = stderr.lines().map(str::trim).find(|l| !l.is_empty());
+    match (output.status.success(), first) {
+        (true, None) => Ok(None),
+        (_, Some(line)) => Ok(Some(line.to_owned())),
+        (false, None) => Ok(Some(format!("ffmpeg exited with {}", output.status))),
+    }
+}
+
+// Parses the input duration in seconds out of a line like
+// "  Duration: 00:03:21.45, start: 0.000000, bitrate: 1024 kb/s"
+fn parse_duration(line: &str) -> Option<f64> {
+    let time = line.trim().strip_prefix("Duration: ")?.split(',').next()?;
+    let mut secs = 0.0;
+    for part in time.split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    if secs > 0.0 {
+        Some(secs)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::{decoded, mp3, SAMPLES};
+    use crate::metadata::Gapless;
+
+    fn fitted(caps: &Capabilities, rate: u32, channels: u8, bits: u8) -> Vec<String> {
+        let meta = Metadata {
+            sample_rate: Some(rate),
+            channels: Some(channels),
+            bits_per_sample: Some(bits),
+            ..Metadata::default()
+        };
+        fit(caps, &meta, Container::Flac)
+    }
+
+    #[test]
+    fn sample_rates_go_down_to_the_highest_allowed() {
+        let cases = [
+            (96000, 44100, 44100),
+            (192000, 44100, 44100),
+            (192000, 48000, 48000),
+            (88200, 48000, 44100),
+            (176400, 96000, 88200),
+            (96000, 88200, 88200),
+            (48000, 44100, 44100),
+            (44100, 32000, 32000),
+            (44100, 30000, 22050),
+        ];
+        for (rate, max, expected) in cases {
+            let caps = Capabilities {
+                max_sample_rate: Some(max),
+                ..Capabilities::default()
+            };
+            let args = fitted(&caps, rate, 2, 16);
+            assert_eq!(
+                args,
+                ["-ar".to_owned(), expected.to_string()],
+                "{} {}",
+                rate,
+                max
+            );
+        }
+    }
+
+    #[test]
+    fn files_within_the_capabilities_are_left_alone() {
+        let caps = Capabilities {
+            max_sample_rate: Some(48000),
+            max_bit_depth: Some(24),
+            max_channels: Some(2),
+            mono: false,
+        };
+        assert!(fitted(&caps, 48000, 2, 24).is_empty());
+        assert_eq!(
+            fitted(&caps, 44100, 6, 32),
+            [
+                "-ac",
+                "2",
+                "-sample_fmt",
+                "s32",
+                "-bits_per_raw_sample",
+                "24"
+            ]
+        );
+
+        let mono = Capabilities {
+            mono: true,
+            max_bit_depth: Some(16),
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            fitted(&mono, 96000, 2, 24),
+            [
+                "-ac",
+                "1",
+                "-sample_fmt",
+                "s16",
+                "-bits_per_raw_sample",
+                "16"
+            ]
+        );
+    }
+
+    #[test]
+    fn copied_mp3s_keep_the_delay_and_padding_of_their_source() {
+        let dir = std::env::temp_dir().join(format!("muco-keep-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("source.mp3"), dir.join("dest.mp3"));
+
+        mp3(&source, 10, b"LAME3.100");
+        let gapless = Gapless {
+            delay: 576,
+            padding: 1000,
+        };
+        metadata::write_gapless(&source, gapless).unwrap();
+        // What ffmpeg copying the audio writes, a header of its own
+        mp3(&dest, 10, b"Lavc58.91");
+
+        keep_gapless(&source, &dest, &Profile::copy(Container::Mp3)).unwrap();
+        assert_eq!(metadata::read_gapless(&dest).unwrap(), Some(gapless));
+        assert_eq!(decoded(&dest), decoded(&source));
+        assert_eq!(decoded(&dest), 10 * SAMPLES - 1576);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
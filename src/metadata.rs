@@ -0,0 +1,785 @@
+/// Tags and stream properties of audio files
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::media::Container;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Tags with lowercase names, `title`, `artist`, `album`, `genre`,
+    /// `date`, `tracknumber`... Repeated tags are joined with "; ".
+    pub tags: BTreeMap<String, String>,
+    /// In seconds
+    pub duration: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    pub bits_per_sample: Option<u8>,
+    /// In kbit/s
+    pub bitrate: Option<u32>,
+    /// Whether there is a picture embedded
+    pub art: bool,
+}
+
+impl Metadata {
+    /// Reads what is supported for the container, nothing for the others
+    pub fn read(path: &Path, container: Container) -> Result<Metadata> {
+        let mut file = File::open(path).context(Kind::Metadata)?;
+
+        let mut meta = match container {
+            Container::Flac => read_flac(&mut file)?,
+            Container::Mp3 => read_mp3(&mut file)?,
+            Container::Webm => Metadata::default(),
+        };
+
+        if meta.bitrate.is_none() {
+            if let (Some(duration), Ok(m)) = (meta.duration, file.metadata()) {
+                if duration > 0.0 {
+                    meta.bitrate = Some((m.len() as f64 * 8.0 / duration / 1000.0) as u32);
+                }
+            }
+        }
+        Ok(meta)
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&str> {
+        match tag {
+            // Vorbis comments use DATE, ID3v2.3 TYER, a year is what people query for
+            "year" => self
+                .tags
+                .get("date")
+                .or_else(|| self.tags.get("year"))
+                .and_then(|d| d.get(..4)),
+            _ => self.tags.get(tag).map(|v| v.as_str()),
+        }
+    }
+
+    fn insert(&mut self, tag: &str, value: String) {
+        let value = value.trim_end_matches('\0').trim().to_owned();
+        if value.is_empty() {
+            return;
+        }
+        self.tags
+            .entry(tag.to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str("; ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+}
+
+/// Byte range of the audio payload of a file, leaving out the tags so that
+/// retagging a file does not change it. The whole file for other containers.
+pub fn audio_range(path: &Path, container: Container) -> Result<Range<u64>> {
+    let mut file = File::open(path).context(Kind::Metadata)?;
+    let len = file.metadata().context(Kind::Metadata)?.len();
+
+    match container {
+        Container::Flac => {
+            let mut start = 4;
+            loop {
+                let mut header = [0; 4];
+                file.seek(SeekFrom::Start(start)).context(Kind::Metadata)?;
+                file.read_exact(&mut header).context(Kind::Metadata)?;
+                start += 4 + u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+                if header[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+            Ok(start..len.max(start))
+        }
+        Container::Mp3 => {
+            let mut header = [0; 10];
+            file.read_exact(&mut header).context(Kind::Metadata)?;
+            let start = if &header[..3] == b"ID3" {
+                // A footer repeats the header at the end of the tag
+                let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+                10 + footer + u64::from(syncsafe(&header[6..10]))
+            } else {
+                0
+            };
+
+            let mut end = len;
+            if read_before(&mut file, end, 128)?.starts_with(b"TAG") {
+                end -= 128;
+            }
+            let ape = read_before(&mut file, end, 32)?;
+            if ape.starts_with(b"APETAGEX") {
+                let size = u32::from_le_bytes([ape[12], ape[13], ape[14], ape[15]]);
+                let has_header = ape[23] & 0x80 != 0;
+                end = end.saturating_sub(u64::from(size) + if has_header { 32 } else { 0 });
+            }
+
+            Ok(start.min(end)..end)
+        }
+        Container::Webm => Ok(0..len),
+    }
+}
+
+/// Checks the structure of a file without decoding it: MP3 frames are walked
+/// one after the other, FLAC metadata blocks must lead to a frame. Returns
+/// what is wrong, if anything. Other containers are not checked.
+pub fn check_frames(path: &Path, container: Container) -> Result<Option<String>> {
+    if container == Container::Webm {
+        return Ok(None);
+    }
+
+    let range = match audio_range(path, container) {
+        Ok(range) if range.start < range.end => range,
+        Ok(_) => return Ok(Some("no audio".to_owned())),
+        Err(_) => return Ok(Some("damaged header".to_owned())),
+    };
+    let mut file = File::open(path).context(Kind::Metadata)?;
+    file.seek(SeekFrom::Start(range.start))
+        .context(Kind::Metadata)?;
+
+    match container {
+        Container::Flac => {
+            let mut sync = [0; 2];
+            file.read_exact(&mut sync).context(Kind::Metadata)?;
+            if sync[0] != 0xff || sync[1] & 0xfe != 0xf8 {
+                return Ok(Some("no frame after the metadata blocks".to_owned()));
+            }
+        }
+        Container::Mp3 => {
+            // Frames are walked by their headers, the audio is not read
+            let total = range.end - range.start;
+            let mut reader = BufReader::new(file);
+            let mut header = [0; 4];
+            if total < 4 {
+                return Ok(Some("no MPEG audio frame".to_owned()));
+            }
+            reader.read_exact(&mut header).context(Kind::Metadata)?;
+
+            // Junk before the first frame is skipped by players too
+            let mut pos = 0;
+            while !is_mpeg_header(&header) {
+                if pos + 4 >= total {
+                    return Ok(Some("no MPEG audio frame".to_owned()));
+                }
+                header.rotate_left(1);
+                reader
+                    .read_exact(&mut header[3..])
+                    .context(Kind::Metadata)?;
+                pos += 1;
+            }
+
+            loop {
+                if !is_mpeg_header(&header) {
+                    return Ok(Some(format!(
+                        "lost frame sync at byte {}",
+                        range.start + pos
+                    )));
+                }
+                let len = match mpeg_frame_len(&header) {
+                    Some(len) => len as u64,
+                    None => break,
+                };
+                if pos + len > total {
+                    return Ok(Some(format!(
+                        "truncated, last frame misses {} bytes",
+                        pos + len - total
+                    )));
+                }
+                pos += len;
+                if pos + 4 > total {
+                    break;
+                }
+                reader
+                    .seek_relative(len as i64 - 4)
+                    .context(Kind::Metadata)?;
+                reader.read_exact(&mut header).context(Kind::Metadata)?;
+            }
+        }
+        Container::Webm => (),
+    }
+    Ok(None)
+}
+
+/// Samples an MP3 encoder adds before and after the audio, which players
+/// leave out to play albums without gaps
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Gapless {
+    pub delay: u16,
+    pub padding: u16,
+}
+
+/// Encoder delay and padding from the LAME header of an MP3, if it has one
+pub fn read_gapless(path: &Path) -> Result<Option<Gapless>> {
+    let (_, frame, at) = match lame_header(path)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let b = &frame[at + 21..at + 24];
+    Ok(Some(Gapless {
+        delay: u16::from(b[0]) << 4 | u16::from(b[1] >> 4),
+        padding: u16::from(b[1] & 0x0f) << 8 | u16::from(b[2]),
+    }))
+}
+
+/// Writes encoder delay and padding into the LAME header of an MP3, returns
+/// whether it has one
+pub fn write_gapless(path: &Path, gapless: Gapless) -> Result<bool> {
+    use std::io::Write;
+
+    let (start, mut frame, at) = match lame_header(path)? {
+        Some(found) => found,
+        None => return Ok(false),
+    };
+    let delay = gapless.delay.min(0xfff);
+    let padding = gapless.padding.min(0xfff);
+    frame[at + 21] = (delay >> 4) as u8;
+    frame[at + 22] = ((delay & 0x0f) << 4) as u8 | (padding >> 8) as u8;
+    frame[at + 23] = padding as u8;
+    // The header checksum covers the frame up to it
+    let crc = crc16(&frame[..at + 34]);
+    frame[at + 34..at + 36].copy_from_slice(&crc.to_be_bytes());
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(Kind::Metadata)?;
+    file.seek(SeekFrom::Start(start)).context(Kind::Metadata)?;
+    file.write_all(&frame[..at + 36]).context(Kind::Metadata)?;
+    Ok(true)
+}
+
+// Offset and content of the first frame of an MP3 when it is a Xing or Info
+// frame carrying a LAME header, and where that header starts in it
+fn lame_header(path: &Path) -> Result<Option<(u64, Vec<u8>, usize)>> {
+    let range = audio_range(path, Container::Mp3)?;
+    let mut file = File::open(path).context(Kind::Metadata)?;
+    file.seek(SeekFrom::Start(range.start))
+        .context(Kind::Metadata)?;
+    let mut data = Vec::new();
+    file.take(4096.min(range.end.saturating_sub(range.start)))
+        .read_to_end(&mut data)
+        .context(Kind::Metadata)?;
+
+    let pos = match data.windows(4).position(is_mpeg_header) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let frame = &data[pos..];
+    let len = match mpeg_frame_len(frame) {
+        Some(len) if len <= frame.len() => len,
+        _ => return Ok(None),
+    };
+
+    // The tag follows the side information, which is shorter for mono and
+    // MPEG 2 frames
+    let mpeg1 = (frame[1] >> 3) & 0x03 == 3;
+    let mono = frame[3] >> 6 == 3;
+    let mut at = 4 + match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    match frame.get(at..at + 8) {
+        Some(tag) if &tag[..4] == b"Xing" || &tag[..4] == b"Info" => {
+            let flags = u32::from_be_bytes([tag[4], tag[5], tag[6], tag[7]]);
+            at += 8;
+            // Frame count, byte count, seek table and quality
+            for (flag, size) in [(1, 4), (2, 4), (4, 100), (8, 4)].iter() {
+                if flags & flag != 0 {
+                    at += size;
+                }
+            }
+        }
+        _ => return Ok(None),
+    }
+
+    // Encoders write their name first, LAME or Lavc
+    match frame.get(at) {
+        Some(c) if at + 36 <= len && c.is_ascii_alphanumeric() => {
+            Ok(Some((range.start + pos as u64, frame[..len].to_vec(), at)))
+        }
+        _ => Ok(None),
+    }
+}
+
+// CRC-16 of LAME headers, the reflected 0x8005 polynomial
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Reads up to `len` bytes ending at `end`
+fn read_before(file: &mut File, end: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(end.saturating_sub(len)))
+        .context(Kind::Metadata)?;
+    read_block(file, len.min(end) as usize)
+}
+
+fn read_flac(file: &mut File) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).context(Kind::Metadata)?;
+    if &magic != b"fLaC" {
+        Err(Kind::Metadata)?
+    }
+
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header).context(Kind::Metadata)?;
+        let last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        match header[0] & 0x7f {
+            // STREAMINFO
+            0 => {
+                let block = read_block(file, len)?;
+                if block.len() >= 18 {
+                    let rate = (u32::from(block[10]) << 12)
+                        | (u32::from(block[11]) << 4)
+                        | (u32::from(block[12]) >> 4);
+                    let samples = (u64::from(block[13] & 0x0f) << 32)
+                        | u64::from(u32::from_be_bytes([
+                            block[14], block[15], block[16], block[17],
+                        ]));
+                    meta.sample_rate = Some(rate);
+                    meta.channels = Some(((block[12] >> 1) & 0x07) + 1);
+                    meta.bits_per_sample = Some((((block[12] & 0x01) << 4) | (block[13] >> 4)) + 1);
+                    if rate > 0 && samples > 0 {
+                        meta.duration = Some(samples as f64 / f64::from(rate));
+                    }
+                }
+            }
+            // VORBIS_COMMENT
+            4 => {
+                let block = read_block(file, len)?;
+                read_vorbis_comments(&block, &mut meta);
+            }
+            // PICTURE
+            6 => {
+                meta.art = true;
+                file.seek(SeekFrom::Current(len as i64))
+                    .context(Kind::Metadata)?;
+            }
+            _ => {
+                file.seek(SeekFrom::Current(len as i64))
+                    .context(Kind::Metadata)?;
+            }
+        }
+
+        if last {
+            break;
+        }
+    }
+    Ok(meta)
+}
+
+fn read_block(file: &mut File, len: usize) -> Result<Vec<u8>> {
+    let mut block = vec![0; len];
+    file.read_exact(&mut block).context(Kind::Metadata)?;
+    Ok(block)
+}
+
+fn read_vorbis_comments(block: &[u8], meta: &mut Metadata) {
+    let u32_at = |at: usize| {
+        block
+            .get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+
+    let mut at = match u32_at(0) {
+        Some(vendor) => 4 + vendor,
+        None => return,
+    };
+    let count = u32_at(at).unwrap_or_default();
+    at += 4;
+
+    for _ in 0..count {
+        let len = match u32_at(at) {
+            Some(len) => len,
+            None => return,
+        };
+        if let Some(comment) = block.get(at + 4..at + 4 + len) {
+            if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+                meta.insert(key, value.to_owned());
+            }
+        }
+        at += 4 + len;
+    }
+}
+
+fn read_mp3(file: &mut File) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    let mut header = [0; 10];
+    file.read_exact(&mut header).context(Kind::Metadata)?;
+
+    let mut audio_start = 0;
+    if &header[..3] == b"ID3" {
+        let size = syncsafe(&header[6..10]) as usize;
+        let tag = read_block(file, size)?;
+        read_id3v2(&tag, header[3], &mut meta);
+        audio_start = 10 + size as u64;
+    }
+
+    if meta.tags.is_empty() {
+        read_id3v1(file, &mut meta)?;
+    }
+    read_mpeg_frame(file, audio_start, &mut meta)?;
+    Ok(meta)
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |size, b| (size << 7) | u32::from(b & 0x7f))
+}
+
+fn read_id3v2(tag: &[u8], version: u8, meta: &mut Metadata) {
+    // ID3v2.2 uses three character frame ids, it is rare enough to be ignored
+    if version < 3 {
+        return;
+    }
+
+    let mut at = 0;
+    while at + 10 <= tag.len() {
+        let id = &tag[at..at + 4];
+        if id[0] == 0 {
+            break;
+        }
+
+        let size = if version == 4 {
+            syncsafe(&tag[at + 4..at + 8])
+        } else {
+            u32::from_be_bytes([tag[at + 4], tag[at + 5], tag[at + 6], tag[at + 7]])
+        } as usize;
+        let body = match tag.get(at + 10..at + 10 + size) {
+            Some(body) => body,
+            None => break,
+        };
+        at += 10 + size;
+
+        let name = match id {
+            b"TIT2" => "title",
+            b"TPE1" => "artist",
+            b"TPE2" => "albumartist",
+            b"TALB" => "album",
+            b"TCON" => "genre",
+            b"TCOM" => "composer",
+            b"TYER" | b"TDRC" => "date",
+            b"TRCK" => "tracknumber",
+            b"TPOS" => "discnumber",
+            b"APIC" => {
+                meta.art = true;
+                continue;
+            }
+            b"TXXX" => {
+                let text = decode_text(body);
+                if let Some((key, value)) = text.split_once('\0') {
+                    meta.insert(key, value.to_owned());
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        // ID3v2.4 separates multiple values with NUL
+        for value in decode_text(body).split('\0') {
+            meta.insert(name, value.to_owned());
+        }
+    }
+}
+
+// Text frames start with their encoding
+fn decode_text(body: &[u8]) -> String {
+    let (encoding, text) = match body.split_first() {
+        Some(split) => split,
+        None => return String::new(),
+    };
+
+    match encoding {
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 | 2 => {
+            let mut big_endian = *encoding == 2;
+            let mut text = text;
+            if text.len() >= 2 && (text[..2] == [0xff, 0xfe] || text[..2] == [0xfe, 0xff]) {
+                big_endian = text[0] == 0xfe;
+                text = &text[2..];
+            }
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| {
+                    if big_endian {
+                        u16::from_be_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_le_bytes([c[0], c[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+fn read_id3v1(file: &mut File, meta: &mut Metadata) -> Result<()> {
+    if file.seek(SeekFrom::End(-128)).is_err() {
+        return Ok(());
+    }
+    let tag = read_block(file, 128)?;
+    if &tag[..3] != b"TAG" {
+        return Ok(());
+    }
+
+    let field = |range: std::ops::Range<usize>| tag[range].iter().map(|b| *b as char).collect();
+    meta.insert("title", field(3..33));
+    meta.insert("artist", field(33..63));
+    meta.insert("album", field(63..93));
+    meta.insert("date", field(93..97));
+    Ok(())
+}
+
+const BITRATES: [u32; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+const BITRATES_V2: [u32; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0,
+];
+const RATES: [u32; 3] = [44100, 48000, 32000];
+
+fn is_mpeg_header(h: &[u8]) -> bool {
+    h[0] == 0xff && h[1] & 0xe0 == 0xe0 && (h[2] >> 4) != 0x0f && (h[2] >> 2) & 0x03 != 0x03
+}
+
+// Length of a Layer III frame, None for other layers and free format
+fn mpeg_frame_len(h: &[u8]) -> Option<usize> {
+    let version = (h[1] >> 3) & 0x03;
+    let layer = (h[1] >> 1) & 0x03;
+    if layer != 1 {
+        return None;
+    }
+
+    let (bitrate, samples) = if version == 3 {
+        (BITRATES[usize::from(h[2] >> 4)], 144)
+    } else {
+        (BITRATES_V2[usize::from(h[2] >> 4)], 72)
+    };
+    let rate = RATES[usize::from((h[2] >> 2) & 0x03)]
+        / match version {
+            3 => 1,
+            2 => 2,
+            _ => 4,
+        };
+    let padding = usize::from((h[2] >> 1) & 0x01);
+
+    if bitrate == 0 {
+        None
+    } else {
+        Some((samples * bitrate * 1000 / rate) as usize + padding)
+    }
+}
+
+// Stream properties from the first MPEG audio frame header
+fn read_mpeg_frame(file: &mut File, start: u64, meta: &mut Metadata) -> Result<()> {
+    file.seek(SeekFrom::Start(start)).context(Kind::Metadata)?;
+    let mut buf = Vec::new();
+    file.by_ref()
+        .take(64 * 1024)
+        .read_to_end(&mut buf)
+        .context(Kind::Metadata)?;
+
+    let frame = buf.windows(4).find(|h| is_mpeg_header(h));
+
+    if let Some(h) = frame {
+        // 3 is MPEG 1, 2 MPEG 2 and 0 MPEG 2.5
+        let version = (h[1] >> 3) & 0x03;
+        let bitrate = if version == 3 {
+            BITRATES[usize::from(h[2] >> 4)]
+        } else {
+            BITRATES_V2[usize::from(h[2] >> 4)]
+        };
+        let rate = RATES[usize::from((h[2] >> 2) & 0x03)]
+            / match version {
+                3 => 1,
+                2 => 2,
+                _ => 4,
+            };
+
+        meta.sample_rate = Some(rate);
+        meta.channels = Some(if h[3] >> 6 == 3 { 1 } else { 2 });
+        if bitrate > 0 {
+            meta.bitrate = Some(bitrate);
+            // Only exact for constant bitrate files, which most are
+            if let Ok(m) = file.metadata() {
+                let audio = m.len().saturating_sub(start);
+                meta.duration = Some(audio as f64 * 8.0 / f64::from(bitrate * 1000));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    // MPEG 1 layer III, 128 kbit/s, 44.1 kHz, mono
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0xc0];
+    const FRAME: usize = 417;
+    pub(crate) const SAMPLES: u64 = 1152;
+    const DECODER: u64 = 529;
+
+    /// Writes an MP3 of `frames` silent frames after an Info frame whose
+    /// LAME header is by `encoder`, without delay nor padding
+    pub(crate) fn mp3(path: &Path, frames: u32, encoder: &[u8; 9]) {
+        let mut info = vec![0; FRAME];
+        info[..4].copy_from_slice(&HEADER);
+        let at = 4 + 17;
+        info[at..at + 4].copy_from_slice(b"Info");
+        // Only the frame count
+        info[at + 4..at + 8].copy_from_slice(&1u32.to_be_bytes());
+        info[at + 8..at + 12].copy_from_slice(&frames.to_be_bytes());
+        let lame = at + 12;
+        info[lame..lame + 9].copy_from_slice(encoder);
+        let crc = crc16(&info[..lame + 34]);
+        info[lame + 34..lame + 36].copy_from_slice(&crc.to_be_bytes());
+
+        let mut file = File::create(path).unwrap();
+        file.write_all(&info).unwrap();
+        let mut frame = vec![0; FRAME];
+        frame[..4].copy_from_slice(&HEADER);
+        for _ in 0..frames {
+            file.write_all(&frame).unwrap();
+        }
+    }
+
+    /// Samples a player leaving out the encoder delay and padding gets
+    pub(crate) fn decoded(path: &Path) -> u64 {
+        let source =
+            MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), source, &options, &MetadataOptions::default())
+            .unwrap();
+        let mut format = probed.format;
+        let track = format.default_track().unwrap();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .unwrap();
+
+        let mut samples = 0;
+        while let Ok(packet) = format.next_packet() {
+            samples += decoder.decode(&packet).unwrap().frames() as u64;
+        }
+        samples
+    }
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("muco-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn frames_are_walked_to_the_end() {
+        let dir = dir("frames");
+        let path = dir.join("a.mp3");
+        let check = |content: &[u8]| {
+            std::fs::write(&path, content).unwrap();
+            check_frames(&path, Container::Mp3).unwrap()
+        };
+        mp3(&path, 3, b"LAME3.100");
+        let intact = std::fs::read(&path).unwrap();
+
+        assert_eq!(check(&intact), None);
+        // Junk before the first frame and an ID3v1 tag after the last
+        let mut tagged = b"junk".to_vec();
+        tagged.extend(&intact);
+        tagged.extend(b"TAG");
+        tagged.resize(tagged.len() + 125, 0);
+        assert_eq!(check(&tagged), None);
+
+        let truncated = &intact[..intact.len() - 17];
+        assert_eq!(
+            check(truncated),
+            Some("truncated, last frame misses 17 bytes".to_owned())
+        );
+        let mut damaged = intact.clone();
+        damaged[2 * FRAME] = 0;
+        assert_eq!(
+            check(&damaged),
+            Some(format!("lost frame sync at byte {}", 2 * FRAME))
+        );
+        assert_eq!(check(&[0; 100]), Some("no MPEG audio frame".to_owned()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gapless_headers_are_written_with_their_checksum() {
+        let dir = dir("gapless");
+        let path = dir.join("a.mp3");
+        mp3(&path, 10, b"LAME3.100");
+
+        let gapless = Gapless {
+            delay: 576,
+            padding: 1000,
+        };
+        assert!(write_gapless(&path, gapless).unwrap());
+        assert_eq!(read_gapless(&path).unwrap(), Some(gapless));
+        // Players ignore the header of LAME when its checksum is off
+        assert_eq!(decoded(&path), 10 * SAMPLES - 1576);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn adjacent_tracks_play_back_to_back() {
+        let dir = dir("adjacent");
+        // An album of 30000 samples cut in two, each track encoded on its own
+        let album = 30000;
+        let tracks = [12345u64, album - 12345];
+        let delay = 576;
+
+        let mut played = 0;
+        for (i, length) in tracks.iter().enumerate() {
+            // As LAME does, the padding covers the delay of decoders too
+            let frames = (length + delay + DECODER).div_ceil(SAMPLES);
+            let padding = frames * SAMPLES - length - delay;
+            let path = dir.join(format!("{}.mp3", i));
+            mp3(&path, frames as u32, b"LAME3.100");
+            let gapless = Gapless {
+                delay: delay as u16,
+                padding: padding as u16,
+            };
+            write_gapless(&path, gapless).unwrap();
+
+            assert_eq!(decoded(&path), *length);
+            played += decoded(&path);
+        }
+        assert_eq!(played, album);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
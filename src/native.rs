@@ -0,0 +1,342 @@
+/// Decoding and transcoding without ffmpeg, built with the `native` feature
+///
+/// Anything symphonia decodes, FLAC, MP3 and Vorbis, can be written as FLAC
+/// with the options a sync needs: cutting a track out of a single file
+/// album, downmixing to mono, lowering the bit depth, and tags. Resampling
+/// and gain still take ffmpeg. MP3 output is out of scope, there being no
+/// MP3 encoder to build in, so MP3 devices always need ffmpeg.
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::Path;
+
+use failure::ResultExt;
+use log::{debug, error};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::media::{Container, Profile};
+use crate::metadata::Metadata;
+
+mod flac;
+
+// What a profile asks for, in terms the native backend understands
+#[derive(Default)]
+struct Options {
+    /// In seconds
+    start: f64,
+    length: Option<f64>,
+    channels: Option<usize>,
+    bits: Option<u32>,
+    tags: Vec<(String, String)>,
+}
+
+impl Options {
+    fn parse(profile: &Profile) -> Option<Options> {
+        if profile.format() != Container::Flac {
+            return None;
+        }
+
+        let mut options = Options::default();
+        let mut args = profile.args().iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // Neither pictures nor chapters are written anyway
+                "-vn" => (),
+                "-map_chapters" | "-write_xing" | "-sample_fmt" => {
+                    args.next()?;
+                }
+                "-acodec" | "-codec" | "-codec:a" => match args.next()?.as_str() {
+                    "flac" | "copy" => (),
+                    _ => return None,
+                },
+                "-ss" => options.start = args.next()?.parse().ok()?,
+                "-t" => options.length = Some(args.next()?.parse().ok()?),
+                "-ac" => options.channels = Some(args.next()?.parse().ok()?),
+                "-bits_per_raw_sample" => options.bits = Some(args.next()?.parse().ok()?),
+                "-metadata" => {
+                    let (key, value) = args.next()?.split_once('=')?;
+                    options.tags.push((key.to_owned(), value.to_owned()));
+                }
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+/// Whether `profile` can be transcoded without ffmpeg
+pub fn supports(profile: &Profile) -> bool {
+    Options::parse(profile).is_some()
+}
+
+// A decoder along with the file it reads
+struct Input {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track: u32,
+    rate: u32,
+    channels: usize,
+    bits: u32,
+    frames: Option<u64>,
+    buffer: Option<SampleBuffer<i32>>,
+}
+
+impl Input {
+    fn open(path: &Path) -> std::result::Result<Input, DecodeError> {
+        let file = File::open(path)?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        // Encoder delay and padding of MP3s are left out
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &options,
+            &MetadataOptions::default(),
+        )?;
+        let format = probed.format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(DecodeError::Unsupported("no audio track"))?;
+        let params = &track.codec_params;
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+
+        Ok(Input {
+            track: track.id,
+            rate: params
+                .sample_rate
+                .ok_or(DecodeError::Unsupported("unknown sample rate"))?,
+            channels: params.channels.map(|c| c.count()).unwrap_or(2),
+            // Lossy sources have no bit depth, 16 bits keeps all they have
+            bits: params.bits_per_sample.unwrap_or(16).min(24),
+            frames: params.n_frames,
+            format,
+            decoder,
+            buffer: None,
+        })
+    }
+
+    /// Next samples, interleaved and scaled to the whole i32 range, none at
+    /// the end of the file
+    fn next(&mut self) -> std::result::Result<Option<&[i32]>, DecodeError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(DecodeError::IoError(ref err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            };
+            if packet.track_id() != self.track {
+                continue;
+            }
+
+            let decoded = self.decoder.decode(&packet)?;
+            if decoded.frames() == 0 {
+                continue;
+            }
+            let mut buffer = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            self.buffer = Some(buffer);
+            return Ok(self.buffer.as_ref().map(|b| b.samples()));
+        }
+    }
+}
+
+/// Writes `source` as FLAC into `dest` the way `profile` says, `progress`
+/// getting called with the fraction of the source transcoded so far
+pub fn transcode(
+    source: &Path,
+    dest: &Path,
+    profile: &Profile,
+    progress: &mut dyn FnMut(f64),
+) -> Result<()> {
+    debug!(
+        "Transcoding {} to {} natively",
+        source.display(),
+        dest.display()
+    );
+    let options = match Options::parse(profile) {
+        Some(options) => options,
+        None => {
+            error!("{} needs ffmpeg", profile.id());
+            Err(Kind::Transcode)?
+        }
+    };
+
+    let mut input = match Input::open(source) {
+        Ok(input) => input,
+        Err(err) => {
+            error!("Cannot decode {}: {}", source.display(), err);
+            Err(Kind::Transcode)?
+        }
+    };
+
+    let channels = match options.channels {
+        Some(1) => 1,
+        Some(n) if n < input.channels => {
+            error!(
+                "Downmixing {} to {} channels needs ffmpeg",
+                source.display(),
+                n
+            );
+            Err(Kind::Transcode)?
+        }
+        _ => input.channels,
+    };
+    // Only ever lowered
+    let bits = options.bits.unwrap_or(input.bits).min(input.bits);
+    let rate = f64::from(input.rate);
+    let first = (options.start * rate).round() as u64;
+    let end = options
+        .length
+        .map(|length| first + (length * rate).round() as u64)
+        .or(input.frames);
+
+    let mut writer =
+        flac::Writer::create(dest, input.rate, channels, bits, &tags(source, &options))?;
+    let in_channels = input.channels;
+    let mut position = 0;
+    let mut frame = vec![0; channels];
+
+    loop {
+        let samples = match input.next() {
+            Ok(Some(samples)) => samples,
+            Ok(None) => break,
+            Err(err) => {
+                error!("Cannot decode {}: {}", source.display(), err);
+                Err(Kind::Transcode)?
+            }
+        };
+
+        for chunk in samples.chunks_exact(in_channels) {
+            if position >= first && end.map(|end| position < end).unwrap_or(true) {
+                if channels == 1 && in_channels > 1 {
+                    let sum: i64 = chunk.iter().map(|s| i64::from(*s)).sum();
+                    frame[0] = (sum / in_channels as i64) as i32;
+                } else {
+                    frame.copy_from_slice(chunk);
+                }
+                for sample in frame.iter_mut() {
+                    *sample >>= 32 - bits;
+                }
+                writer.push(&frame)?;
+            }
+            position += 1;
+        }
+
+        if let Some(end) = end {
+            progress(
+                (position.saturating_sub(first) as f64 / (end - first).max(1) as f64).min(1.0),
+            );
+            if position >= end {
+                break;
+            }
+        }
+    }
+
+    writer.finish()?;
+    progress(1.0);
+    Ok(())
+}
+
+// Tags of the source, with those of the profile over them the way ffmpeg
+// names them. Empty ones are removed.
+fn tags(source: &Path, options: &Options) -> Vec<(String, String)> {
+    let container = source
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|e| Container::try_from(e).ok());
+    let mut tags: Vec<(String, String)> = container
+        .and_then(|c| Metadata::read(source, c).ok())
+        .map(|meta| meta.tags.into_iter().collect())
+        .unwrap_or_default();
+
+    for (key, value) in options.tags.iter() {
+        let key = match key.to_ascii_lowercase().as_str() {
+            "track" => "tracknumber".to_owned(),
+            "album_artist" => "albumartist".to_owned(),
+            key => key.to_owned(),
+        };
+        tags.retain(|(k, _)| *k != key);
+        if !value.is_empty() {
+            tags.push((key, value.clone()));
+        }
+    }
+
+    tags.into_iter()
+        .map(|(key, value)| (key.to_ascii_uppercase(), value))
+        .collect()
+}
+
+/// Decodes a whole file, returning the first error it ran into, if any
+pub fn decode(path: &Path) -> Result<Option<String>> {
+    debug!("Decoding {} natively", path.display());
+    let mut input = match Input::open(path) {
+        Ok(input) => input,
+        Err(DecodeError::IoError(err)) if err.kind() != std::io::ErrorKind::UnexpectedEof => {
+            Err(err).context(Kind::Transcode)?
+        }
+        Err(err) => return Ok(Some(err.to_string())),
+    };
+
+    loop {
+        match input.next() {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(None),
+            Err(err) => return Ok(Some(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Encoder;
+    use flac::tests::{decoded, noise, write};
+
+    #[test]
+    fn stereo_is_downmixed_to_mono_and_bits_lowered() {
+        let dir = std::env::temp_dir().join(format!("muco-native-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("stereo.flac"), dir.join("mono.flac"));
+        let samples = noise(10000 * 2, 24);
+        write(&source, 2, 24, &samples);
+
+        let profile = Profile::new(Container::Flac, &Encoder::default())
+            .unwrap()
+            .with_args(vec![
+                "-ac".into(),
+                "1".into(),
+                "-bits_per_raw_sample".into(),
+                "16".into(),
+            ]);
+        transcode(&source, &dest, &profile, &mut |_| ()).unwrap();
+
+        let mono = samples
+            .chunks_exact(2)
+            .map(|s| ((i64::from(s[0] << 8) + i64::from(s[1] << 8)) / 2) as i32 >> 16)
+            .collect();
+        assert_eq!(decoded(&dest), (1, 16, mono));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+/// A FLAC encoder, enough to write what the native backend decodes
+///
+/// Blocks are a fixed 4096 samples, each channel coded on its own with the
+/// best fixed predictor and a single Rice partition, or verbatim when that
+/// is smaller. Files come out a little larger than with libFLAC, and without
+/// the MD5 of the audio, which is allowed.
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use failure::ResultExt;
+
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+
+const BLOCK: usize = 4096;
+
+pub struct Writer {
+    file: BufWriter<File>,
+    rate: u32,
+    bits: u32,
+    // Samples of the block being filled, by channel
+    block: Vec<Vec<i32>>,
+    frames: u64,
+    samples: u64,
+    min_frame: usize,
+    max_frame: usize,
+}
+
+impl Writer {
+    /// Starts a file of `channels` channels of `bits` bits, tagged with
+    /// Vorbis comments like `TITLE=...`
+    pub fn create(
+        path: &Path,
+        rate: u32,
+        channels: usize,
+        bits: u32,
+        tags: &[(String, String)],
+    ) -> Result<Writer> {
+        let mut writer = Writer {
+            file: BufWriter::new(File::create(path).context(Kind::Transcode)?),
+            rate,
+            bits,
+            block: vec![Vec::with_capacity(BLOCK); channels],
+            frames: 0,
+            samples: 0,
+            min_frame: 0,
+            max_frame: 0,
+        };
+
+        let mut comments = Vec::new();
+        let vendor = b"muco";
+        comments.extend(&(vendor.len() as u32).to_le_bytes());
+        comments.extend(vendor);
+        comments.extend(&(tags.len() as u32).to_le_bytes());
+        for (key, value) in tags {
+            let comment = format!("{}={}", key, value);
+            comments.extend(&(comment.len() as u32).to_le_bytes());
+            comments.extend(comment.as_bytes());
+        }
+
+        let info = writer.stream_info();
+        let file = &mut writer.file;
+        file.write_all(b"fLaC").context(Kind::Transcode)?;
+        file.write_all(&block_header(false, 0, info.len()))
+            .context(Kind::Transcode)?;
+        file.write_all(&info).context(Kind::Transcode)?;
+        file.write_all(&block_header(true, 4, comments.len()))
+            .context(Kind::Transcode)?;
+        file.write_all(&comments).context(Kind::Transcode)?;
+        Ok(writer)
+    }
+
+    /// Adds one sample for every channel
+    pub fn push(&mut self, frame: &[i32]) -> Result<()> {
+        for (channel, sample) in self.block.iter_mut().zip(frame) {
+            channel.push(*sample);
+        }
+        if self.block[0].len() == BLOCK {
+            self.write_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Writes what is left, then the length of the stream at the start
+    pub fn finish(mut self) -> Result<()> {
+        if !self.block[0].is_empty() {
+            self.write_frame()?;
+        }
+
+        let info = self.stream_info();
+        // After "fLaC" and the header of the STREAMINFO block
+        self.file
+            .seek(SeekFrom::Start(8))
+            .context(Kind::Transcode)?;
+        self.file.write_all(&info).context(Kind::Transcode)?;
+        self.file.flush().context(Kind::Transcode)?;
+        Ok(())
+    }
+
+    fn stream_info(&self) -> Vec<u8> {
+        let mut w = Bits::default();
+        w.put(16, BLOCK as u64);
+        w.put(16, BLOCK as u64);
+        w.put(24, self.min_frame as u64);
+        w.put(24, self.max_frame as u64);
+        w.put(20, u64::from(self.rate));
+        w.put(3, self.block.len() as u64 - 1);
+        w.put(5, u64::from(self.bits) - 1);
+        w.put(4, self.samples >> 32);
+        w.put(32, self.samples & 0xffff_ffff);
+        // Unknown MD5 of the audio
+        for _ in 0..4 {
+            w.put(32, 0);
+        }
+        w.bytes
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        let len = self.block[0].len();
+        let mut w = Bits::default();
+        // Sync code, fixed block size
+        w.put(16, 0xfff8);
+        // Block size in 16 bits after the frame number, sample rate and
+        // size from STREAMINFO
+        w.put(4, 0b0111);
+        w.put(4, 0);
+        w.put(4, self.block.len() as u64 - 1);
+        w.put(4, 0);
+        for byte in utf8(self.frames) {
+            w.put(8, u64::from(byte));
+        }
+        w.put(16, len as u64 - 1);
+        let crc = crc8(&w.bytes);
+        w.put(8, u64::from(crc));
+
+        for channel in self.block.iter() {
+            subframe(&mut w, channel, self.bits);
+        }
+        w.align();
+        let crc = crc16(&w.bytes);
+        w.put(16, u64::from(crc));
+
+        self.file.write_all(&w.bytes).context(Kind::Transcode)?;
+        if self.frames == 0 || w.bytes.len() < self.min_frame {
+            self.min_frame = w.bytes.len();
+        }
+        self.max_frame = self.max_frame.max(w.bytes.len());
+        self.frames += 1;
+        self.samples += len as u64;
+        for channel in self.block.iter_mut() {
+            channel.clear();
+        }
+        Ok(())
+    }
+}
+
+fn block_header(last: bool, kind: u8, len: usize) -> [u8; 4] {
+    let len = len as u32;
+    [
+        (u8::from(last) << 7) | kind,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ]
+}
+
+fn subframe(w: &mut Bits, samples: &[i32], bits: u32) {
+    if samples.iter().all(|s| *s == samples[0]) {
+        w.put(8, 0);
+        w.put_signed(bits, i64::from(samples[0]));
+        return;
+    }
+
+    let samples: Vec<i64> = samples.iter().map(|s| i64::from(*s)).collect();
+    let (order, residual) = (0..=4)
+        .filter(|order| *order < samples.len())
+        .map(|order| (order, residual(&samples, order)))
+        .min_by_key(|(_, r)| r.iter().map(|r| r.unsigned_abs()).sum::<u64>())
+        .unwrap_or_else(|| (0, samples.clone()));
+
+    let k = rice_parameter(&residual);
+    let coded: u64 = residual.iter().map(|r| 1 + k + (zigzag(*r) >> k)).sum();
+    let param_bits = if k <= 14 { 4 } else { 5 };
+    let fixed = (order as u64) * u64::from(bits) + 6 + param_bits + coded;
+
+    if fixed >= samples.len() as u64 * u64::from(bits) {
+        w.put(8, 0b10);
+        for sample in samples {
+            w.put_signed(bits, sample);
+        }
+        return;
+    }
+
+    w.put(8, (0b1000 | order as u64) << 1);
+    for sample in &samples[..order] {
+        w.put_signed(bits, *sample);
+    }
+    // Rice coding with 4 or 5 bit parameters, one partition
+    w.put(2, if param_bits == 4 { 0 } else { 1 });
+    w.put(4, 0);
+    w.put(param_bits as u32, k);
+    for r in residual {
+        let u = zigzag(r);
+        w.put_unary(u >> k);
+        w.put(k as u32, u);
+    }
+}
+
+// What the fixed predictor of `order` leaves of the samples after the first
+// `order` ones
+fn residual(x: &[i64], order: usize) -> Vec<i64> {
+    (order..x.len())
+        .map(|n| match order {
+            0 => x[n],
+            1 => x[n] - x[n - 1],
+            2 => x[n] - 2 * x[n - 1] + x[n - 2],
+            3 => x[n] - 3 * x[n - 1] + 3 * x[n - 2] - x[n - 3],
+            _ => x[n] - 4 * x[n - 1] + 6 * x[n - 2] - 4 * x[n - 3] + x[n - 4],
+        })
+        .collect()
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+// Parameter near the log2 of the mean of the residual
+fn rice_parameter(residual: &[i64]) -> u64 {
+    let sum: u64 = residual.iter().map(|r| zigzag(*r)).sum();
+    let count = residual.len().max(1) as u64;
+    let mut k = 0;
+    while k < 30 && count << (k + 1) < sum {
+        k += 1;
+    }
+    k
+}
+
+// Frame numbers are coded like UTF-8 characters
+fn utf8(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+
+    let mut len = 2;
+    while len < 7 && value >> (5 * len + 1) != 0 {
+        len += 1;
+    }
+    let mut bytes = vec![(0xff00u16 >> len) as u8 | (value >> (6 * (len - 1))) as u8];
+    for i in (0..len - 1).rev() {
+        bytes.push(0x80 | ((value >> (6 * i)) & 0x3f) as u8);
+    }
+    bytes
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Bits written most significant first
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    acc: u64,
+    count: u32,
+}
+
+impl Bits {
+    // Up to 32 bits at a time
+    fn put(&mut self, count: u32, value: u64) {
+        if count == 0 {
+            return;
+        }
+        self.acc = (self.acc << count) | (value & ((1 << count) - 1));
+        self.count += count;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.acc >> self.count) as u8);
+        }
+        self.acc &= (1 << self.count) - 1;
+    }
+
+    fn put_signed(&mut self, count: u32, value: i64) {
+        self.put(count, value as u64);
+    }
+
+    fn put_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.put(32, 0);
+            zeros -= 32;
+        }
+        self.put(zeros as u32, 0);
+        self.put(1, 1);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.put(8 - self.count, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::native::Input;
+
+    // Samples of a file, interleaved and at the bit depth of the file, along
+    // with its channels and bits
+    pub(crate) fn decoded(path: &Path) -> (usize, u32, Vec<i32>) {
+        let mut input = Input::open(path).unwrap();
+        let (channels, bits) = (input.channels, input.bits);
+        let mut samples = Vec::new();
+        while let Some(decoded) = input.next().unwrap() {
+            samples.extend(decoded.iter().map(|s| s >> (32 - bits)));
+        }
+        (channels, bits, samples)
+    }
+
+    // Noise within `bits` bits, the same on every run
+    pub(crate) fn noise(count: usize, bits: u32) -> Vec<i32> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..count)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1);
+                ((state >> 33) as i32) >> (32 - bits)
+            })
+            .collect()
+    }
+
+    pub(crate) fn write(path: &Path, channels: usize, bits: u32, samples: &[i32]) {
+        let tags = vec![("TITLE".to_owned(), "Noise".to_owned())];
+        let mut writer = Writer::create(path, 44100, channels, bits, &tags).unwrap();
+        for frame in samples.chunks_exact(channels) {
+            writer.push(frame).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("muco-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn samples_come_back_as_written() {
+        let dir = dir("flac");
+        for (channels, bits) in [(2, 16), (1, 24), (2, 24), (1, 8)] {
+            // A constant block, a noisy one, then a short last one
+            let mut samples = vec![-3; BLOCK * channels];
+            samples.extend(noise((BLOCK + 1000) * channels, bits));
+            // A smooth one too, coded with a predictor
+            samples.extend((0..BLOCK * channels).map(|i| (i as i32 / 4) % 100 - 50));
+
+            let path = dir.join(format!("{}-{}.flac", channels, bits));
+            write(&path, channels, bits, &samples);
+            assert_eq!(decoded(&path), (channels, bits, samples));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
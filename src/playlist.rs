@@ -0,0 +1,472 @@
+/// Playlist parsing and rewriting
+use std::convert::TryFrom;
+use std::path::{Component, Path, PathBuf};
+
+use failure::ResultExt;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{MucoError, MucoErrorKind as Kind, MucoResult as Result};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    M3u8,
+    M3u,
+    Pls,
+    /// Do not sync playlists
+    None,
+}
+
+impl TryFrom<&str> for PlaylistFormat {
+    type Error = MucoError;
+
+    fn try_from(val: &str) -> Result<Self> {
+        match val.to_ascii_lowercase().as_ref() {
+            "m3u8" => Ok(PlaylistFormat::M3u8),
+            "m3u" => Ok(PlaylistFormat::M3u),
+            "pls" => Ok(PlaylistFormat::Pls),
+            _ => Err(Kind::Playlist)?,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Utf8,
+    /// Characters outside of Latin-1 are written as `?`
+    Latin1,
+}
+
+/// How playlists are written to a device
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaylistStyle {
+    pub format: PlaylistFormat,
+    pub encoding: Encoding,
+    pub separator: char,
+    /// Write paths from the device root (`/Music/a.mp3`) instead of
+    /// relative to the playlist
+    pub absolute: bool,
+}
+
+impl Default for PlaylistStyle {
+    fn default() -> Self {
+        PlaylistStyle {
+            format: PlaylistFormat::M3u8,
+            encoding: Encoding::Utf8,
+            separator: '/',
+            absolute: false,
+        }
+    }
+}
+
+impl PlaylistStyle {
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            PlaylistFormat::M3u8 => "m3u8",
+            PlaylistFormat::M3u | PlaylistFormat::None => "m3u",
+            PlaylistFormat::Pls => "pls",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    /// In seconds, -1 when unknown
+    pub duration: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playlist {
+    pub path: PathBuf,
+    pub entries: Vec<Entry>,
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| PlaylistFormat::try_from(e).is_ok())
+        .unwrap_or(false)
+}
+
+impl Playlist {
+    /// Reads a playlist, resolving its entries against the playlist location
+    pub fn read(path: &Path) -> Result<Playlist> {
+        let bytes = std::fs::read(path).context(Kind::Playlist)?;
+        // Plain .m3u files are often Latin-1, which is not always valid UTF-8
+        let content = match String::from_utf8(bytes) {
+            Ok(content) => content,
+            Err(err) => err.into_bytes().iter().map(|b| *b as char).collect(),
+        };
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let format = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(PlaylistFormat::try_from)
+            .unwrap_or(Err(Kind::Playlist.into()))?;
+
+        let entries = match format {
+            PlaylistFormat::Pls => parse_pls(&content, base),
+            _ => parse_m3u(&content, base),
+        };
+        debug!("Read {} entries from {}", entries.len(), path.display());
+
+        Ok(Playlist {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+}
+
+fn parse_m3u(content: &str, base: &Path) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut info: Option<(i64, String)> = None;
+
+    for line in content
+        .lines()
+        .map(|l| l.trim_start_matches('\u{feff}').trim())
+    {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let mut parts = extinf.splitn(2, ',');
+            let duration = parts
+                .next()
+                .and_then(|d| d.trim().parse().ok())
+                .unwrap_or(-1);
+            info = Some((duration, parts.next().unwrap_or_default().to_owned()));
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else if let Some(path) = resolve(line, base) {
+            let (duration, title) = info.take().unwrap_or((-1, String::new()));
+            entries.push(Entry {
+                path,
+                title: Some(title).filter(|t| !t.is_empty()),
+                duration,
+            });
+        }
+    }
+    entries
+}
+
+fn parse_pls(content: &str, base: &Path) -> Vec<Entry> {
+    let mut entries: Vec<(usize, Entry)> = Vec::new();
+    let mut titles = Vec::new();
+    let mut lengths = Vec::new();
+
+    for line in content.lines().map(str::trim) {
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let lower = key.to_ascii_lowercase();
+        let index = |prefix: &str| lower.strip_prefix(prefix).and_then(|n| n.parse().ok());
+
+        if let Some(n) = index("file") {
+            if let Some(path) = resolve(value, base) {
+                entries.push((
+                    n,
+                    Entry {
+                        path,
+                        title: None,
+                        duration: -1,
+                    },
+                ));
+            }
+        } else if let Some(n) = index("title") {
+            titles.push((n, value.to_owned()));
+        } else if let Some(n) = index("length") {
+            lengths.push((n, value.parse().unwrap_or(-1)));
+        }
+    }
+
+    entries.sort_by_key(|(n, _)| *n);
+    entries
+        .into_iter()
+        .map(|(n, mut entry)| {
+            entry.title = titles.iter().find(|(i, _)| *i == n).map(|(_, t)| t.clone());
+            entry.duration = lengths
+                .iter()
+                .find(|(i, _)| *i == n)
+                .map_or(-1, |(_, l)| *l);
+            entry
+        })
+        .collect()
+}
+
+// Streams are not files, they cannot be synced
+fn resolve(entry: &str, base: &Path) -> Option<PathBuf> {
+    if entry.contains("://") && !entry.starts_with("file://") {
+        return None;
+    }
+
+    let entry = match entry.strip_prefix("file://") {
+        Some(uri) => percent_decode(uri.strip_prefix("localhost").unwrap_or(uri)),
+        None => entry.to_owned(),
+    };
+    // Playlists made on Windows use backslashes
+    let entry = if cfg!(unix) {
+        entry.replace('\\', "/")
+    } else {
+        entry
+    };
+
+    Some(normalize(&base.join(entry)))
+}
+
+// URIs escape spaces and non-ASCII characters as `%20` and the like
+fn percent_decode(uri: &str) -> String {
+    let mut bytes = Vec::with_capacity(uri.len());
+    let mut rest = uri.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) if byte == b'%' => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Lexically removes `.` and `..` so that entries compare equal to scanned paths
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Renders entries with the path they have on the device, written in `style`.
+/// `dest` is where the playlist itself goes and `root` the device root.
+pub fn render(
+    entries: &[(&Entry, PathBuf)],
+    dest: &Path,
+    root: &Path,
+    style: &PlaylistStyle,
+) -> Vec<u8> {
+    let base = dest.parent().unwrap_or(root);
+    let path_of = |file: &Path| {
+        let components: Vec<String> = if style.absolute {
+            std::iter::once(String::new())
+                .chain(components(file.strip_prefix(root).unwrap_or(file)))
+                .collect()
+        } else {
+            relative(file, base)
+        };
+        components.join(&style.separator.to_string())
+    };
+
+    let mut out = String::new();
+    match style.format {
+        PlaylistFormat::Pls => {
+            out.push_str("[playlist]\r\n");
+            for (n, (entry, file)) in entries.iter().enumerate() {
+                out.push_str(&format!("File{}={}\r\n", n + 1, path_of(file)));
+                if let Some(ref title) = entry.title {
+                    out.push_str(&format!("Title{}={}\r\n", n + 1, title));
+                }
+                out.push_str(&format!("Length{}={}\r\n", n + 1, entry.duration));
+            }
+            out.push_str(&format!(
+                "NumberOfEntries={}\r\nVersion=2\r\n",
+                entries.len()
+            ));
+        }
+        _ => {
+            out.push_str("#EXTM3U\r\n");
+            for (entry, file) in entries {
+                if let Some(ref title) = entry.title {
+                    out.push_str(&format!("#EXTINF:{},{}\r\n", entry.duration, title));
+                }
+                out.push_str(&path_of(file));
+                out.push_str("\r\n");
+            }
+        }
+    }
+
+    match style.encoding {
+        Encoding::Utf8 => out.into_bytes(),
+        Encoding::Latin1 => out
+            .chars()
+            .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+            .collect(),
+    }
+}
+
+fn components(path: &Path) -> impl Iterator<Item = String> + '_ {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+}
+
+fn relative(file: &Path, base: &Path) -> Vec<String> {
+    let file: Vec<_> = components(file).collect();
+    let base: Vec<_> = components(base).collect();
+    let common = file
+        .iter()
+        .zip(base.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    std::iter::repeat_n("..".to_owned(), base.len() - common)
+        .chain(file[common..].iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, title: Option<&str>, duration: i64) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            title: title.map(|t| t.to_owned()),
+            duration,
+        }
+    }
+
+    #[test]
+    fn m3u_entries_are_resolved_against_the_playlist() {
+        let content = "\u{feff}#EXTM3U\r\n\
+            #EXTINF:215,Artist - Title\r\n\
+            ../A/01 Title.flac\r\n\
+            \r\n\
+            # a comment\r\n\
+            /music/B/02.mp3\r\n\
+            Windows\\03.mp3\r\n\
+            http://radio.example/stream\r\n\
+            file:///music/My%20Song%C3%A9.flac\r\n\
+            file://localhost/music/b%2.flac\r\n";
+        assert_eq!(
+            parse_m3u(content, Path::new("/music/Lists")),
+            vec![
+                entry("/music/A/01 Title.flac", Some("Artist - Title"), 215),
+                entry("/music/B/02.mp3", None, -1),
+                entry("/music/Lists/Windows/03.mp3", None, -1),
+                entry("/music/My Songé.flac", None, -1),
+                entry("/music/b%2.flac", None, -1),
+            ]
+        );
+    }
+
+    #[test]
+    fn pls_entries_are_read_in_order() {
+        let content = "[playlist]\r\n\
+            File2=b.mp3\r\n\
+            Title2=B\r\n\
+            file1=file:///music/a%20b.mp3\r\n\
+            Length1=61\r\n\
+            File3=https://radio.example/stream\r\n\
+            NumberOfEntries=3\r\n";
+        assert_eq!(
+            parse_pls(content, Path::new("/music")),
+            vec![
+                entry("/music/a b.mp3", None, 61),
+                entry("/music/b.mp3", Some("B"), -1),
+            ]
+        );
+    }
+
+    #[test]
+    fn latin1_playlists_are_read() {
+        let dir = std::env::temp_dir().join(format!("muco-playlist-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("list.m3u");
+        std::fs::write(&path, b"Bj\xf6rk.mp3\n").unwrap();
+
+        let playlist = Playlist::read(&path).unwrap();
+        let bjork = dir.join("Björk.mp3");
+        assert_eq!(
+            playlist.entries,
+            vec![entry(bjork.to_str().unwrap(), None, -1)]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paths_are_made_relative_to_the_playlist() {
+        let relative = |file: &str, base: &str| relative(Path::new(file), Path::new(base));
+        assert_eq!(relative("/d/A/a.mp3", "/d/A"), vec!["a.mp3"]);
+        assert_eq!(relative("/d/A/a.mp3", "/d/Lists"), vec!["..", "A", "a.mp3"]);
+        assert_eq!(relative("/d/a.mp3", "/d/L/M"), vec!["..", "..", "a.mp3"]);
+    }
+
+    // What is written reads back the same, whatever the style
+    #[test]
+    fn rendered_playlists_read_back() {
+        let root = Path::new("/dev");
+        let dest = root.join("Lists/mix.m3u");
+        let entries = [
+            entry("/music/A/a.flac", Some("Ça va"), 120),
+            entry("/music/B/b.flac", None, -1),
+        ];
+        let files = [root.join("A/a.mp3"), root.join("B/b.mp3")];
+        let rendered: Vec<(&Entry, PathBuf)> = entries.iter().zip(files.iter().cloned()).collect();
+        let expected = vec![
+            entry("/dev/A/a.mp3", Some("Ça va"), 120),
+            entry("/dev/B/b.mp3", None, -1),
+        ];
+
+        for format in [
+            PlaylistFormat::M3u8,
+            PlaylistFormat::M3u,
+            PlaylistFormat::Pls,
+        ] {
+            for (encoding, absolute) in [(Encoding::Utf8, false), (Encoding::Latin1, true)] {
+                let style = PlaylistStyle {
+                    format,
+                    encoding,
+                    separator: '\\',
+                    absolute,
+                };
+                let bytes = render(&rendered, &dest, root, &style);
+                let content: String = match encoding {
+                    Encoding::Utf8 => String::from_utf8(bytes).unwrap(),
+                    Encoding::Latin1 => bytes.iter().map(|b| *b as char).collect(),
+                };
+                assert!(content.split_terminator('\n').all(|l| l.ends_with('\r')));
+
+                // Absolute paths are from the device root
+                let base = match absolute {
+                    true => root.to_path_buf(),
+                    false => root.join("Lists"),
+                };
+                let read = match format {
+                    PlaylistFormat::Pls => parse_pls(&content, &base),
+                    _ => parse_m3u(&content, &base),
+                };
+                let read: Vec<Entry> = read
+                    .into_iter()
+                    .map(|mut e| {
+                        e.path = match absolute {
+                            true => root.join(e.path.strip_prefix("/").unwrap()),
+                            false => e.path,
+                        };
+                        e
+                    })
+                    .collect();
+                assert_eq!(read, expected, "{:?} {:?}", format, encoding);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/// Device settings known to suit a kind of device
+///
+/// A few presets are bundled with muco, more can be set in the configuration
+/// file under `[preset.<name>]`, which win over the bundled ones.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use failure::ResultExt;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{
+    ArtConf, Capabilities, Config, DeviceEntry, Encoder, GainConf, Gapless, Layout,
+};
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::media::Container;
+use crate::playlist::PlaylistStyle;
+
+const BUNDLED: &str = include_str!("presets.toml");
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub format: Container,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub companions: Vec<String>,
+    #[serde(default)]
+    pub gapless: Gapless,
+    #[serde(default)]
+    pub playlists: PlaylistStyle,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    #[serde(default)]
+    pub encoder: Encoder,
+    #[serde(default)]
+    pub gain: GainConf,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub art: ArtConf,
+}
+
+impl Preset {
+    /// Every preset by name, those of the configuration replacing the bundled
+    /// ones of the same name
+    pub fn all(conf: &Config) -> Result<BTreeMap<String, Preset>> {
+        let mut presets: BTreeMap<String, Preset> = toml::from_str(BUNDLED).context(Kind::Serde)?;
+        presets.extend(
+            conf.get_presets()
+                .iter()
+                .map(|(name, preset)| (name.clone(), preset.clone())),
+        );
+        Ok(presets)
+    }
+
+    pub fn get(conf: &Config, name: &str) -> Result<Preset> {
+        match Preset::all(conf)?.remove(name) {
+            Some(preset) => Ok(preset),
+            None => {
+                error!("No preset named {}, see `muco device presets`", name);
+                Err(Kind::Nonexistant)?
+            }
+        }
+    }
+
+    /// Settings of a new device, to be adjusted before adding it
+    pub fn entry(&self, name: String, location: PathBuf) -> DeviceEntry {
+        DeviceEntry {
+            name,
+            location,
+            excludes: None,
+            format: self.format,
+            only_playlists: Vec::new(),
+            query: None,
+            companions: self.companions.clone(),
+            gapless: self.gapless,
+            playlists: self.playlists.clone(),
+            gain: self.gain.clone(),
+            capabilities: self.capabilities.clone(),
+            encoder: self.encoder.clone(),
+            layout: self.layout.clone(),
+            art: self.art.clone(),
+            libraries: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_presets_are_read() {
+        let presets: BTreeMap<String, Preset> = toml::from_str(BUNDLED).unwrap();
+        let car = &presets["generic-car-fat32"];
+        assert_eq!(car.encoder.bitrate, Some(256));
+        assert_eq!(car.layout.max_depth, Some(8));
+        assert!(car.layout.fat_names);
+
+        let entry = car.entry("Car".to_owned(), PathBuf::from("/run/media/aj/CAR"));
+        assert_eq!(entry.encoder, car.encoder);
+        assert_eq!(entry.layout, car.layout);
+    }
+}
@@ -0,0 +1,583 @@
+/// Sync progress reporting
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::error::MucoError;
+use crate::{Action, Job};
+
+/// Observer of a running sync. Every method does nothing by default.
+pub trait Progress {
+    /// Called once, before anything gets written, with the work ahead
+    fn planned(&mut self, _files: usize, _bytes: u64) {}
+
+    fn started(&mut self, _job: &Job) {}
+
+    /// Fraction of the current file done, only reported while transcoding
+    fn advanced(&mut self, _job: &Job, _fraction: f64) {}
+
+    fn finished(&mut self, _job: &Job) {}
+
+    /// The job could not be completed. Jobs which cannot be run at all, e.g.
+    /// for lack of ffmpeg, fail right after `planned` without being started.
+    fn failed(&mut self, _job: &Job, _err: &MucoError) {}
+
+    /// The device already has the file, reported right after `planned`
+    fn skipped(&mut self, _job: &Job) {}
+
+    /// `kept` goes where the job would have written, reported right after `planned`
+    fn collided(&mut self, _job: &Job, _kept: &Path) {}
+
+    /// The file would not fit on the device, reported right after `planned`
+    fn unfit(&mut self, _job: &Job) {}
+
+    /// The sync could not even be planned, called instead of `planned`
+    /// before `done`
+    fn aborted(&mut self, _err: &MucoError) {}
+
+    fn done(&mut self) {}
+}
+
+/// Keeps the totals of a sync to work out throughput and ETA
+#[derive(Clone, Debug, Default)]
+pub struct Tracker {
+    files: usize,
+    bytes: u64,
+    files_done: usize,
+    bytes_done: u64,
+    failed: usize,
+    skipped: usize,
+    // Bytes of the current file accounted for through `advance`
+    current: u64,
+    started: Option<Instant>,
+}
+
+impl Tracker {
+    pub fn plan(&mut self, files: usize, bytes: u64) {
+        self.files = files;
+        self.bytes = bytes;
+        self.started = Some(Instant::now());
+    }
+
+    pub fn advance(&mut self, job: &Job, fraction: f64) {
+        self.current = (job.size as f64 * fraction) as u64;
+    }
+
+    pub fn finish(&mut self, job: &Job) {
+        self.files_done += 1;
+        self.bytes_done += job.size;
+        self.current = 0;
+    }
+
+    /// Failed files count as done, there is no more work left on them
+    pub fn fail(&mut self, job: &Job) {
+        self.finish(job);
+        self.failed += 1;
+    }
+
+    /// Files left out of the sync, already there or not fitting
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Files done, failed ones included, out of the planned files
+    pub fn files(&self) -> (usize, usize) {
+        (self.files_done, self.files)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.bytes_done + self.current, self.bytes)
+    }
+
+    /// Fraction of the planned bytes done
+    pub fn fraction(&self) -> f64 {
+        let (done, total) = self.bytes();
+        if total == 0 {
+            1.0
+        } else {
+            done as f64 / total as f64
+        }
+    }
+
+    /// Source bytes processed per second
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes().0 as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let (done, total) = self.bytes();
+        let throughput = self.throughput();
+
+        if throughput > 0.0 {
+            Some(Duration::from_secs_f64(
+                total.saturating_sub(done) as f64 / throughput,
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.map(|s| s.elapsed()).unwrap_or_default()
+    }
+}
+
+/// Prints a line per file, for logs and pipes
+#[derive(Debug, Default)]
+pub struct Lines {
+    tracker: Tracker,
+}
+
+impl Progress for Lines {
+    fn planned(&mut self, files: usize, bytes: u64) {
+        self.tracker.plan(files, bytes);
+        println!("{} files to sync, {}", files, human_bytes(bytes));
+    }
+
+    fn started(&mut self, job: &Job) {
+        let (done, total) = self.tracker.files();
+        let verb = match job.action {
+            Action::Copy => "Copy to",
+            Action::Transcode(_) => "Transcoding to:",
+            Action::Playlist { .. } => "Writing playlist",
+            Action::Art { .. } => "Writing art",
+        };
+        println!(
+            "[{}/{}] {} {} (ETA {})",
+            done + 1,
+            total,
+            verb,
+            job.dest.display(),
+            human_eta(self.tracker.eta())
+        );
+    }
+
+    fn finished(&mut self, job: &Job) {
+        self.tracker.finish(job);
+    }
+
+    fn failed(&mut self, job: &Job, err: &MucoError) {
+        self.tracker.fail(job);
+        println!("Failed {}: {}", job.dest.display(), err);
+    }
+
+    fn skipped(&mut self, job: &Job) {
+        self.tracker.skip();
+        println!("Skipping {}, already on the device", job.dest.display());
+    }
+
+    fn unfit(&mut self, job: &Job) {
+        self.tracker.skip();
+        println!("Not syncing {}, no space left", job.source.display());
+    }
+
+    fn collided(&mut self, job: &Job, kept: &Path) {
+        self.tracker.skip();
+        println!(
+            "Not syncing {}, {} goes to {}",
+            job.source.display(),
+            kept.display(),
+            job.dest.display()
+        );
+    }
+
+    fn aborted(&mut self, err: &MucoError) {
+        println!("Cannot sync: {}", err);
+    }
+
+    fn done(&mut self) {
+        println!("{}", summary(&self.tracker));
+    }
+}
+
+/// Redraws a progress bar on a terminal
+#[derive(Debug, Default)]
+pub struct Bar {
+    tracker: Tracker,
+    current: String,
+    drawn: Option<Instant>,
+}
+
+impl Bar {
+    const WIDTH: usize = 30;
+
+    fn draw(&mut self, force: bool) {
+        // Redrawing on every ffmpeg progress line only makes the terminal flicker
+        let recent = |d: Instant| d.elapsed() < Duration::from_millis(100);
+        if !force && self.drawn.is_some_and(recent) {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+
+        let filled = (self.tracker.fraction() * Self::WIDTH as f64) as usize;
+        let (done, total) = self.tracker.files();
+        let mut line = format!(
+            "[{}{}] {:3.0}% {}/{} {}/s ETA {} {}",
+            "=".repeat(filled.min(Self::WIDTH)),
+            " ".repeat(Self::WIDTH - filled.min(Self::WIDTH)),
+            self.tracker.fraction() * 100.0,
+            done,
+            total,
+            human_bytes(self.tracker.throughput() as u64),
+            human_eta(self.tracker.eta()),
+            self.current
+        );
+        line.truncate(line.char_indices().nth(120).map_or(line.len(), |(i, _)| i));
+
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl Progress for Bar {
+    fn planned(&mut self, files: usize, bytes: u64) {
+        self.tracker.plan(files, bytes);
+        self.draw(true);
+    }
+
+    fn started(&mut self, job: &Job) {
+        self.current = job
+            .dest
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.draw(true);
+    }
+
+    fn advanced(&mut self, job: &Job, fraction: f64) {
+        self.tracker.advance(job, fraction);
+        self.draw(false);
+    }
+
+    fn finished(&mut self, job: &Job) {
+        self.tracker.finish(job);
+        self.draw(false);
+    }
+
+    fn failed(&mut self, job: &Job, err: &MucoError) {
+        self.tracker.fail(job);
+        eprintln!("\r\x1b[2KFailed {}: {}", job.dest.display(), err);
+        self.draw(true);
+    }
+
+    // Only counted, the device may have most of the library already
+    fn skipped(&mut self, _job: &Job) {
+        self.tracker.skip();
+    }
+
+    fn unfit(&mut self, job: &Job) {
+        self.tracker.skip();
+        eprintln!(
+            "\r\x1b[2KNot syncing {}, no space left",
+            job.source.display()
+        );
+        self.draw(true);
+    }
+
+    fn collided(&mut self, job: &Job, kept: &Path) {
+        self.tracker.skip();
+        eprintln!(
+            "\r\x1b[2KNot syncing {}, {} goes to {}",
+            job.source.display(),
+            kept.display(),
+            job.dest.display()
+        );
+        self.draw(true);
+    }
+
+    fn aborted(&mut self, err: &MucoError) {
+        eprintln!("Cannot sync: {}", err);
+    }
+
+    fn done(&mut self) {
+        self.current.clear();
+        self.draw(true);
+        eprintln!("\n{}", summary(&self.tracker));
+    }
+}
+
+/// Sync events with stable names and fields, for frontends which would
+/// rather consume a stream than implement [`Progress`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    Plan {
+        files: usize,
+        bytes: u64,
+    },
+    FileStart {
+        device: String,
+        source: String,
+        dest: String,
+        action: &'static str,
+        bytes: u64,
+    },
+    FileDone {
+        device: String,
+        source: String,
+        dest: String,
+    },
+    Skip {
+        device: String,
+        source: String,
+        dest: String,
+        reason: &'static str,
+    },
+    Error {
+        device: String,
+        source: String,
+        dest: String,
+        message: String,
+    },
+    /// The sync could not be planned, nothing was written
+    Abort {
+        message: String,
+    },
+    Summary {
+        files: usize,
+        bytes: u64,
+        skipped: usize,
+        failed: usize,
+        seconds: f64,
+    },
+}
+
+/// Turns the progress of a sync into [`Event`]s handed to `sink`
+pub struct Events<F: FnMut(Event)> {
+    sink: F,
+    tracker: Tracker,
+}
+
+impl<F: FnMut(Event)> Events<F> {
+    pub fn new(sink: F) -> Self {
+        Events {
+            sink,
+            tracker: Tracker::default(),
+        }
+    }
+}
+
+impl<F: FnMut(Event)> Progress for Events<F> {
+    fn planned(&mut self, files: usize, bytes: u64) {
+        self.tracker.plan(files, bytes);
+        (self.sink)(Event::Plan { files, bytes });
+    }
+
+    fn started(&mut self, job: &Job) {
+        (self.sink)(Event::FileStart {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+            action: match job.action {
+                Action::Copy => "copy",
+                Action::Transcode(_) => "transcode",
+                Action::Playlist { .. } => "playlist",
+                Action::Art { .. } => "art",
+            },
+            bytes: job.size,
+        });
+    }
+
+    fn finished(&mut self, job: &Job) {
+        self.tracker.finish(job);
+        (self.sink)(Event::FileDone {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+        });
+    }
+
+    fn failed(&mut self, job: &Job, err: &MucoError) {
+        self.tracker.fail(job);
+        (self.sink)(Event::Error {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+            message: err.to_string(),
+        });
+    }
+
+    fn skipped(&mut self, job: &Job) {
+        self.tracker.skip();
+        (self.sink)(Event::Skip {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+            reason: "exists",
+        });
+    }
+
+    fn collided(&mut self, job: &Job, _kept: &Path) {
+        self.tracker.skip();
+        (self.sink)(Event::Skip {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+            reason: "collision",
+        });
+    }
+
+    fn unfit(&mut self, job: &Job) {
+        self.tracker.skip();
+        (self.sink)(Event::Skip {
+            device: job.device.clone(),
+            source: job.source.to_string_lossy().into_owned(),
+            dest: job.dest.to_string_lossy().into_owned(),
+            reason: "no-space",
+        });
+    }
+
+    fn aborted(&mut self, err: &MucoError) {
+        (self.sink)(Event::Abort {
+            message: err.to_string(),
+        });
+    }
+
+    fn done(&mut self) {
+        let (files, _) = self.tracker.files();
+        (self.sink)(Event::Summary {
+            files: files - self.tracker.failed(),
+            bytes: self.tracker.bytes().0,
+            skipped: self.tracker.skipped(),
+            failed: self.tracker.failed(),
+            seconds: self.tracker.elapsed().as_secs_f64(),
+        });
+    }
+}
+
+fn summary(tracker: &Tracker) -> String {
+    let (files, _) = tracker.files();
+    let mut summary = format!(
+        "Synced {} files in {}",
+        files - tracker.failed(),
+        human_duration(tracker.elapsed())
+    );
+    if tracker.skipped() > 0 {
+        summary.push_str(&format!(", {} skipped", tracker.skipped()));
+    }
+    if tracker.failed() > 0 {
+        summary.push_str(&format!(", {} failed", tracker.failed()));
+    }
+    summary
+}
+
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+fn human_eta(eta: Option<Duration>) -> String {
+    eta.map(human_duration).unwrap_or_else(|| "--".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MucoErrorKind;
+    use std::path::PathBuf;
+
+    fn job(action: Action) -> Job {
+        Job {
+            device: "Car".to_owned(),
+            source: PathBuf::from("/music/a.flac"),
+            dest: PathBuf::from("/car/a.mp3"),
+            action,
+            size: 1000,
+            track: None,
+        }
+    }
+
+    // Frontends rely on these names, they are not to change
+    #[test]
+    fn events_keep_their_names_and_fields() {
+        let mut events = Vec::new();
+        let mut progress = Events::new(|event| events.push(event));
+        let (copy, art) = (job(Action::Copy), job(Action::Art { max_size: None }));
+
+        progress.planned(2, 2000);
+        progress.skipped(&copy);
+        progress.collided(&copy, Path::new("/music/b.flac"));
+        progress.unfit(&copy);
+        progress.started(&copy);
+        progress.finished(&copy);
+        progress.started(&art);
+        progress.failed(&art, &MucoErrorKind::Transcode.into());
+        progress.aborted(&MucoErrorKind::Index.into());
+        progress.done();
+
+        let paths = r#""device":"Car","source":"/music/a.flac","dest":"/car/a.mp3""#;
+        let expected = vec![
+            r#"{"event":"plan","files":2,"bytes":2000}"#.to_owned(),
+            format!(r#"{{"event":"skip",{},"reason":"exists"}}"#, paths),
+            format!(r#"{{"event":"skip",{},"reason":"collision"}}"#, paths),
+            format!(r#"{{"event":"skip",{},"reason":"no-space"}}"#, paths),
+            format!(
+                r#"{{"event":"file-start",{},"action":"copy","bytes":1000}}"#,
+                paths
+            ),
+            format!(r#"{{"event":"file-done",{}}}"#, paths),
+            format!(
+                r#"{{"event":"file-start",{},"action":"art","bytes":1000}}"#,
+                paths
+            ),
+            format!(
+                r#"{{"event":"error",{},"message":"Transcoding error"}}"#,
+                paths
+            ),
+            r#"{"event":"abort","message":"Cannot read/write library index"}"#.to_owned(),
+        ];
+        let json: Vec<String> = events
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap())
+            .collect();
+        assert_eq!(json[..expected.len()], expected[..]);
+
+        // The time taken aside
+        let summary = &json[expected.len()];
+        let fields =
+            r#"{"event":"summary","files":1,"bytes":2000,"skipped":3,"failed":1,"seconds":"#;
+        assert!(summary.starts_with(fields), "{}", summary);
+    }
+
+    #[test]
+    fn summaries_count_skipped_files() {
+        let mut bar = Bar::default();
+        bar.tracker.plan(2, 2000);
+        bar.skipped(&job(Action::Copy));
+        bar.tracker.finish(&job(Action::Copy));
+        bar.tracker.fail(&job(Action::Copy));
+        assert!(summary(&bar.tracker).ends_with(", 1 skipped, 1 failed"));
+    }
+}
@@ -0,0 +1,419 @@
+/// Query language over library metadata
+///
+/// A query is made of terms like `genre:jazz`, `year>=1960` or
+/// `artist="Kenny G"`, combined with `AND`, `OR`, `NOT` and parentheses.
+/// Terms next to each other are ANDed and a bare word matches titles,
+/// artists, albums and genres. Quoted words are never operators. `:`
+/// matches a part of the value, ignoring case, the other operators compare
+/// numbers when both sides are numbers.
+use std::convert::TryFrom;
+use std::path::Path;
+
+use log::error;
+
+use crate::error::{MucoError, MucoErrorKind as Kind, MucoResult as Result};
+use crate::metadata::Metadata;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Contains,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Term {
+        field: String,
+        op: Op,
+        value: String,
+    },
+    /// Matches titles, artists, albums and genres
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term(String, Op, String),
+    Word(String),
+}
+
+impl TryFrom<&str> for Query {
+    type Error = MucoError;
+
+    fn try_from(query: &str) -> Result<Self> {
+        let tokens = tokenize(query)?;
+        let mut parser = Parser { tokens, at: 0 };
+        let parsed = parser.or()?;
+
+        if parser.at < parser.tokens.len() {
+            error!("Unexpected {:?} in query", parser.tokens[parser.at]);
+            Err(Kind::Query)?
+        }
+        Ok(parsed)
+    }
+}
+
+impl Query {
+    /// Whether a file matches. `path` and `format` can be queried too.
+    pub fn matches(&self, path: &Path, meta: &Metadata) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(path, meta) && b.matches(path, meta),
+            Query::Or(a, b) => a.matches(path, meta) || b.matches(path, meta),
+            Query::Not(q) => !q.matches(path, meta),
+            Query::Text(text) => ["title", "artist", "album", "genre"]
+                .iter()
+                .filter_map(|f| meta.get(f))
+                .any(|v| contains(v, text)),
+            Query::Term { field, op, value } => {
+                let path = path.to_string_lossy();
+                let actual = match field.as_str() {
+                    "path" => Some(path.as_ref()),
+                    "format" => path.rsplit('.').next(),
+                    field => meta.get(field),
+                };
+
+                match actual {
+                    Some(actual) => compare(actual, *op, value),
+                    // A missing tag is different from anything
+                    None => *op == Op::Ne,
+                }
+            }
+        }
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+fn compare(actual: &str, op: Op, value: &str) -> bool {
+    use std::cmp::Ordering;
+
+    if op == Op::Contains {
+        return contains(actual, value);
+    }
+
+    // Track numbers come as "3/12"
+    let number = |s: &str| {
+        s.split('/')
+            .next()
+            .and_then(|n| n.trim().parse::<f64>().ok())
+    };
+    let ordering = match (number(actual), number(value)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => Some(actual.to_lowercase().cmp(&value.to_lowercase())),
+    };
+
+    match (op, ordering) {
+        (Op::Eq, Some(o)) => o == Ordering::Equal,
+        (Op::Ne, Some(o)) => o != Ordering::Equal,
+        (Op::Lt, Some(o)) => o == Ordering::Less,
+        (Op::Le, Some(o)) => o != Ordering::Greater,
+        (Op::Gt, Some(o)) => o == Ordering::Greater,
+        (Op::Ge, Some(o)) => o != Ordering::Less,
+        _ => false,
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    let is_special = |c: char| c.is_whitespace() || "():<>=!\"".contains(c);
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+        } else {
+            let (word, quoted) = read_value(&mut chars, is_special)?;
+            let op = match chars.peek() {
+                Some(':') => Some(Op::Contains),
+                Some('=') => Some(Op::Eq),
+                Some('!') => Some(Op::Ne),
+                Some('<') => Some(Op::Lt),
+                Some('>') => Some(Op::Gt),
+                _ => None,
+            };
+
+            match op {
+                Some(op) => {
+                    chars.next();
+                    let op = match (op, chars.peek()) {
+                        (Op::Ne, Some('=')) | (Op::Lt, Some('=')) | (Op::Gt, Some('=')) => {
+                            chars.next();
+                            match op {
+                                Op::Lt => Op::Le,
+                                Op::Gt => Op::Ge,
+                                op => op,
+                            }
+                        }
+                        (Op::Ne, _) => Err(Kind::Query)?,
+                        (op, _) => op,
+                    };
+                    let (value, _) = read_value(&mut chars, is_special)?;
+                    tokens.push(Token::Term(word.to_lowercase(), op, value));
+                }
+                None if quoted => tokens.push(Token::Word(word)),
+                None => tokens.push(match word.as_str() {
+                    "AND" | "and" => Token::And,
+                    "OR" | "or" => Token::Or,
+                    "NOT" | "not" => Token::Not,
+                    _ => Token::Word(word),
+                }),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// A bare word or a double quoted string, and whether it was quoted
+fn read_value(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    is_special: impl Fn(char) -> bool,
+) -> Result<(String, bool)> {
+    let mut value = String::new();
+
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok((value, true)),
+                Some('\\') => value.extend(chars.next()),
+                Some(c) => value.push(c),
+                None => {
+                    error!("Unterminated string in query");
+                    Err(Kind::Query)?
+                }
+            }
+        }
+    }
+
+    while let Some(&c) = chars.peek() {
+        if is_special(c) {
+            break;
+        }
+        value.push(c);
+        chars.next();
+    }
+
+    if value.is_empty() {
+        error!("Expected a word in query");
+        Err(Kind::Query)?
+    }
+    Ok((value, false))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut query = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.at += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut query = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.at += 1,
+                Some(Token::Or) | Some(Token::Close) | None => break,
+                // Juxtaposed terms are ANDed
+                Some(_) => (),
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+        Ok(query)
+    }
+
+    fn not(&mut self) -> Result<Query> {
+        if self.peek() == Some(&Token::Not) {
+            self.at += 1;
+            Ok(Query::Not(Box::new(self.not()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Query> {
+        let token = self.peek().cloned();
+        self.at += 1;
+
+        match token {
+            Some(Token::Open) => {
+                let query = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    error!("Missing closing parenthesis in query");
+                    Err(Kind::Query)?
+                }
+                self.at += 1;
+                Ok(query)
+            }
+            Some(Token::Term(field, op, value)) => Ok(Query::Term { field, op, value }),
+            Some(Token::Word(word)) => Ok(Query::Text(word)),
+            token => {
+                error!("Unexpected {:?} in query", token);
+                Err(Kind::Query)?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<Query> {
+        Query::try_from(query)
+    }
+
+    fn term(field: &str, op: Op, value: &str) -> Query {
+        Query::Term {
+            field: field.to_owned(),
+            op,
+            value: value.to_owned(),
+        }
+    }
+
+    fn text(word: &str) -> Query {
+        Query::Text(word.to_owned())
+    }
+
+    fn and(a: Query, b: Query) -> Query {
+        Query::And(Box::new(a), Box::new(b))
+    }
+
+    fn or(a: Query, b: Query) -> Query {
+        Query::Or(Box::new(a), Box::new(b))
+    }
+
+    fn not(q: Query) -> Query {
+        Query::Not(Box::new(q))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a OR b AND c").unwrap(),
+            or(text("a"), and(text("b"), text("c")))
+        );
+        assert_eq!(
+            parse("a b or c").unwrap(),
+            or(and(text("a"), text("b")), text("c"))
+        );
+        assert_eq!(parse("NOT a b").unwrap(), and(not(text("a")), text("b")));
+        assert_eq!(parse("not not a").unwrap(), not(not(text("a"))));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(
+            parse("(a OR b) c").unwrap(),
+            and(or(text("a"), text("b")), text("c"))
+        );
+        assert_eq!(
+            parse("NOT (a OR (b))").unwrap(),
+            not(or(text("a"), text("b")))
+        );
+    }
+
+    #[test]
+    fn quoted_words_are_values() {
+        assert_eq!(
+            parse(r#"artist:"Kenny G""#).unwrap(),
+            term("artist", Op::Contains, "Kenny G")
+        );
+        assert_eq!(parse(r#""AND""#).unwrap(), text("AND"));
+        assert_eq!(
+            parse(r#"title:"OR" "not""#).unwrap(),
+            and(term("title", Op::Contains, "OR"), text("not"))
+        );
+        assert_eq!(
+            parse(r#"title="a \"b\" (c)""#).unwrap(),
+            term("title", Op::Eq, r#"a "b" (c)"#)
+        );
+    }
+
+    #[test]
+    fn fields_are_compared() {
+        let cases = [
+            ("Year>=1960", term("year", Op::Ge, "1960")),
+            ("year<=1960", term("year", Op::Le, "1960")),
+            ("year<1960", term("year", Op::Lt, "1960")),
+            ("year>1960", term("year", Op::Gt, "1960")),
+            ("genre=Jazz", term("genre", Op::Eq, "Jazz")),
+            ("genre!=jazz", term("genre", Op::Ne, "jazz")),
+            ("genre:jazz", term("genre", Op::Contains, "jazz")),
+        ];
+        for (query, expected) in cases.iter() {
+            assert_eq!(&parse(query).unwrap(), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        for query in [
+            "",
+            "a AND",
+            "OR a",
+            "(a",
+            "a)",
+            "genre:",
+            "genre!jazz",
+            r#"title:"open"#,
+            "()",
+        ] {
+            let err = parse(query).expect_err(query);
+            assert_eq!(err.kind(), Kind::Query, "{}", query);
+        }
+    }
+
+    #[test]
+    fn tags_are_matched() {
+        let mut meta = Metadata::default();
+        for (tag, value) in [
+            ("title", "So What"),
+            ("artist", "Miles Davis"),
+            ("genre", "Jazz"),
+            ("date", "1959-08-17"),
+            ("tracknumber", "1/5"),
+        ] {
+            meta.tags.insert(tag.to_owned(), value.to_owned());
+        }
+        let path = Path::new("/music/Kind of Blue/01.flac");
+        let matches = |query: &str| parse(query).unwrap().matches(path, &meta);
+
+        assert!(matches("jazz"));
+        assert!(matches("miles year>=1959 year<1960"));
+        assert!(matches("tracknumber=1 format=flac"));
+        assert!(matches(r#"path:"kind of""#));
+        assert!(matches("album!=x"));
+        assert!(!matches("album=x"));
+        assert!(!matches("genre:rock OR NOT davis"));
+    }
+}
@@ -1,7 +1,9 @@
+use std::ffi::CString;
 use std::fs::File;
 use std::io::Write;
 /// Utility functions for use in other modules
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use crate::config::Config;
 use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
@@ -56,6 +58,13 @@ pub(crate) fn get_files<'a>(
         })
 }
 
+/// The configuration file, none when it was not written yet
+pub(crate) fn find_config_file() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("muco")
+        .ok()?
+        .find_config_file("muco.toml")
+}
+
 pub(crate) fn get_config_file() -> Result<PathBuf> {
     let xdg_dirs =
         xdg::BaseDirectories::with_prefix("muco").expect("Cannot create configuration directory");
@@ -77,3 +86,19 @@ pub(crate) fn get_config_file() -> Result<PathBuf> {
         }
     }
 }
+
+// Bytes available to unprivileged users on the filesystem of `path`
+pub(crate) fn free_space(path: &Path) -> Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).context(Kind::Nonexistant)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        Err(std::io::Error::last_os_error()).context(Kind::Nonexistant)?
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Lowercase hexadecimal digits of a digest
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,159 @@
+/// Checking that the files on a device are still intact
+///
+/// Cheap flash media and FAT filesystems unplugged mid-write lose data
+/// silently. Files recorded in the device manifest are compared against their
+/// recorded size and hash, and the audio files are decoded with ffmpeg, or
+/// have their frames walked when ffmpeg is not there. Pictures, lyrics and
+/// other companion files are only compared.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::device::Device;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::manifest::{self, Manifest};
+use crate::media::{self, Container};
+use crate::metadata::check_frames;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// Recorded in the manifest but gone from the device
+    Missing,
+    /// Shorter or longer than when it was synced
+    Size { expected: u64, found: u64 },
+    /// Same size, different contents
+    Hash,
+    /// Does not decode, with the first error found
+    Decode(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(fmt, "missing"),
+            Problem::Size { expected, found } if found < expected => {
+                write!(fmt, "truncated, {} bytes instead of {}", found, expected)
+            }
+            Problem::Size { expected, found } => {
+                write!(fmt, "{} bytes instead of {}", found, expected)
+            }
+            Problem::Hash => write!(fmt, "contents changed since synced"),
+            Problem::Decode(err) => write!(fmt, "corrupt, {}", err),
+        }
+    }
+}
+
+/// Checks the files of a device, returning the damaged ones sorted. Files
+/// are compared against the manifest when recorded in it, and audio files
+/// decoded when `decode` is set. `checking` is called before each file.
+pub fn verify(
+    dev: &Device,
+    decode: bool,
+    checking: &mut dyn FnMut(&Path),
+) -> Result<Vec<(PathBuf, Problem)>> {
+    let root = dev.location();
+    if !root.is_dir() {
+        Err(Kind::Nonexistant)?
+    }
+
+    let manifest = Manifest::load(root);
+    let files: BTreeSet<PathBuf> = dev
+        .source()
+        .keys()
+        .cloned()
+        .chain(manifest.files().map(|(path, _)| root.join(path)))
+        .collect();
+
+    let mut ffmpeg = true;
+    let mut damaged = Vec::new();
+    for path in files {
+        checking(&path);
+
+        let record = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(|relative| manifest.get(relative));
+        let problem = match record {
+            Some(record) => match path.metadata() {
+                Err(_) => Some(Problem::Missing),
+                Ok(meta) if meta.len() != record.size => Some(Problem::Size {
+                    expected: record.size,
+                    found: meta.len(),
+                }),
+                Ok(_) if manifest::hash(&path)? != record.hash => Some(Problem::Hash),
+                Ok(_) => None,
+            },
+            None => None,
+        };
+
+        let problem = match (problem, dev.source().get(&path)) {
+            (Some(problem), _) => Some(problem),
+            (None, Some(container)) if decode => check(&path, *container, &mut ffmpeg)?,
+            (None, _) => None,
+        };
+
+        if let Some(problem) = problem {
+            debug!("{}: {}", path.display(), problem);
+            damaged.push((path, problem));
+        }
+    }
+
+    Ok(damaged)
+}
+
+// Decodes with ffmpeg until it turns out not to be there, then falls back
+// to walking the frames
+fn check(path: &Path, container: Container, ffmpeg: &mut bool) -> Result<Option<Problem>> {
+    if *ffmpeg {
+        match media::decode(path) {
+            Ok(err) => return Ok(err.map(Problem::Decode)),
+            Err(err) => {
+                warn!("Cannot run ffmpeg, only checking frames: {}", err);
+                *ffmpeg = false;
+            }
+        }
+    }
+
+    Ok(check_frames(path, container)?.map(Problem::Decode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn companions_are_compared_but_not_decoded() {
+        let root = std::env::temp_dir().join(format!("muco-verify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("A")).unwrap();
+        let mut manifest = Manifest::default();
+        for (file, content) in [("A/folder.jpg", "jpeg"), ("A/1.lrc", "[00:01]la")] {
+            std::fs::write(root.join(file), content).unwrap();
+            manifest
+                .record(&root, &root.join(file), Path::new(file), None)
+                .unwrap();
+        }
+        manifest.save(&root).unwrap();
+        std::fs::write(root.join("A/1.lrc"), "[00:01]lu").unwrap();
+
+        let conf: Config = toml::from_str(&format!(
+            r#"
+            libraries = []
+            [[devices]]
+            name = "Car"
+            location = "{}"
+            format = "Mp3"
+            "#,
+            root.display()
+        ))
+        .unwrap();
+        let dev = Device::get(&conf, Some("Car".to_owned())).unwrap();
+        let damaged = verify(&dev[0], true, &mut |_| ()).unwrap();
+        assert_eq!(damaged, vec![(root.join("A/1.lrc"), Problem::Hash)]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+/// Mount watching and automatic synchronisation
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use failure::ResultExt;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, error, info, warn};
+use walkdir::WalkDir;
+
+use crate::cache::Cache;
+use crate::config::{Config, Duplicates};
+use crate::device::Device;
+use crate::error::{MucoErrorKind as Kind, MucoResult as Result};
+use crate::library::Library;
+use crate::media::{Container, Profile};
+use crate::progress::Lines;
+use crate::Action;
+
+pub const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    Start,
+    Finish,
+    Failure,
+}
+
+impl From<HookEvent> for &'static str {
+    fn from(event: HookEvent) -> &'static str {
+        match event {
+            HookEvent::Start => "start",
+            HookEvent::Finish => "finish",
+            HookEvent::Failure => "failure",
+        }
+    }
+}
+
+/// Polls a mountinfo file and syncs configured devices as they get mounted
+pub struct Watcher {
+    mountinfo: PathBuf,
+    interval: Duration,
+    // Names of the devices which were mounted during the last poll
+    mounted: HashSet<String>,
+    libraries: Option<LibraryWatcher>,
+}
+
+impl Watcher {
+    pub fn new(mountinfo: PathBuf, interval: Duration) -> Self {
+        Watcher {
+            mountinfo,
+            interval,
+            mounted: HashSet::new(),
+            libraries: None,
+        }
+    }
+
+    /// Also keep watching the libraries, pre-transcoding new files
+    pub fn watch_libraries(mut self, conf: &Config) -> Result<Self> {
+        self.libraries = Some(LibraryWatcher::new(conf)?);
+        Ok(self)
+    }
+
+    /// Watch forever, re-reading the configuration on every poll so that
+    /// devices added while the daemon is running are picked up. A
+    /// configuration which cannot be read leaves the previous one in use.
+    pub fn run(&mut self) -> Result<()> {
+        info!("Watching {} for devices", self.mountinfo.display());
+        let mut conf = Config::get()?;
+        loop {
+            // Errors may only last one poll, like a mount table read while
+            // it changes, keep watching
+            if let Some(ref mut libraries) = self.libraries {
+                if let Err(err) = libraries.poll(&conf) {
+                    error!("Watching the libraries failed: {}", err);
+                }
+            }
+            let appeared = self.poll(&conf).unwrap_or_else(|err| {
+                error!("Looking for devices failed: {}", err);
+                Vec::new()
+            });
+
+            for name in appeared {
+                let libraries = self.libraries.as_mut().map(|l| l.for_sync(&conf));
+                // A failed sync is reported through the hooks, keep watching
+                let _ = sync_device(&conf, &name, libraries);
+            }
+            thread::sleep(self.interval);
+
+            match Config::get() {
+                Ok(new) => conf = new,
+                Err(err) => warn!("Keeping the previous configuration: {}", err),
+            }
+        }
+    }
+
+    /// Reads the mount table once and returns the names of the configured
+    /// devices which appeared since the previous call.
+    pub fn poll(&mut self, conf: &Config) -> Result<Vec<String>> {
+        let mount_points = mount_points(&self.mountinfo)?;
+        let mounted: HashSet<String> = conf
+            .get_devices()
+            .iter()
+            .filter(|d| is_mounted(&d.location, &mount_points))
+            .map(|d| d.name.clone())
+            .collect();
+
+        let mut appeared: Vec<String> = mounted.difference(&self.mounted).cloned().collect();
+        appeared.sort();
+
+        for name in self.mounted.difference(&mounted) {
+            info!("Device {} was unmounted", name);
+        }
+        self.mounted = mounted;
+
+        Ok(appeared)
+    }
+}
+
+/// Sync every online library to a single device, running the configured hooks.
+/// Libraries which are already scanned are used as is.
+pub fn sync_device(conf: &Config, name: &str, libraries: Option<Vec<Library>>) -> Result<()> {
+    info!("Device {} mounted, starting sync", name);
+    run_hook(conf, HookEvent::Start, name, None);
+
+    let res = conf.clone().get_online().and_then(|online| {
+        let libraries = match libraries {
+            Some(libraries) => libraries,
+            None => Library::get(&online, None)?,
+        };
+        let devices = Device::get(&online, Some(name.to_owned()))?;
+        crate::sync(&online, devices, libraries, &mut Lines::default())
+    });
+
+    match res {
+        Ok(()) => {
+            info!("Device {} synced", name);
+            run_hook(conf, HookEvent::Finish, name, None);
+        }
+        Err(ref err) => {
+            error!("Syncing device {} failed: {}", name, err);
+            run_hook(conf, HookEvent::Failure, name, Some(&err.to_string()));
+        }
+    }
+
+    res
+}
+
+/// Keeps the scanned libraries up to date through inotify and transcodes new
+/// files into the cache for every configured device format.
+pub struct LibraryWatcher {
+    inotify: Inotify,
+    // Watched directory and the index of its library
+    dirs: HashMap<WatchDescriptor, (PathBuf, usize)>,
+    libraries: Vec<Library>,
+    cache: Cache,
+}
+
+impl LibraryWatcher {
+    pub fn new(conf: &Config) -> Result<Self> {
+        let online = conf.clone().get_online()?;
+        let mut watcher = LibraryWatcher {
+            inotify: Inotify::init().context(Kind::LibraryWatch)?,
+            dirs: HashMap::new(),
+            libraries: Library::get(&online, None)?,
+            cache: Cache::open(conf.get_cache())?,
+        };
+
+        for idx in 0..watcher.libraries.len() {
+            let location = watcher.libraries[idx].location().clone();
+            watcher.add_watches(&location, idx)?;
+        }
+
+        Ok(watcher)
+    }
+
+    pub fn libraries(&self) -> &[Library] {
+        &self.libraries
+    }
+
+    /// A copy of the libraries for a sync. Audio hashes are worked out on
+    /// the watched libraries rather than on the copy, so that every sync
+    /// does not hash the files again.
+    pub fn for_sync(&mut self, conf: &Config) -> Vec<Library> {
+        if conf.get_sync().duplicates == Duplicates::First {
+            for library in self.libraries.iter_mut() {
+                library.hash();
+            }
+        }
+        self.libraries.clone()
+    }
+
+    // inotify is not recursive, every directory needs its own watch. Returns
+    // the files which are already present below `dir`.
+    fn add_watches(&mut self, dir: &Path, idx: usize) -> Result<Vec<PathBuf>> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::CREATE
+            | WatchMask::DELETE;
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_dir() {
+                debug!("Watching {}", entry.path().display());
+                let wd = self
+                    .inotify
+                    .add_watch(entry.path(), mask)
+                    .context(Kind::LibraryWatch)?;
+                self.dirs.insert(wd, (entry.path().to_path_buf(), idx));
+            } else {
+                files.push(entry.into_path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Handles the pending filesystem events without blocking
+    pub fn poll(&mut self, conf: &Config) -> Result<()> {
+        let mut buffer = [0; 4096];
+        let mut changes = Vec::new();
+
+        loop {
+            let events = self
+                .inotify
+                .read_events(&mut buffer)
+                .context(Kind::LibraryWatch)?;
+
+            let before = changes.len();
+            for event in events {
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                if let (Some((dir, idx)), Some(name)) = (self.dirs.get(&event.wd), event.name) {
+                    changes.push((dir.join(name), *idx, event.mask));
+                }
+            }
+
+            if changes.len() == before {
+                break;
+            }
+        }
+
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let devices: Vec<Device> = conf
+            .get_devices()
+            .iter()
+            .filter_map(|entry| match Device::configured(conf, entry) {
+                Ok(dev) => Some(dev),
+                Err(err) => {
+                    warn!("Not pre-transcoding for {}: {}", entry.name, err);
+                    None
+                }
+            })
+            .collect();
+
+        for (path, idx, mask) in changes {
+            if mask.contains(EventMask::ISDIR) {
+                if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    // Files may have landed in the directory before it got watched
+                    for file in self.add_watches(&path, idx)? {
+                        added.push((file, idx));
+                    }
+                }
+            } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                removed.push((path, idx));
+            } else if mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) {
+                added.push((path, idx));
+            }
+        }
+
+        for (path, idx) in removed {
+            let library = &mut self.libraries[idx];
+            if let Ok(file) = path.strip_prefix(library.location()) {
+                if library.remove(&path).is_some() {
+                    info!("Library {}: {} removed", library.name(), file.display());
+                }
+            }
+        }
+
+        let mut cached_now = Vec::new();
+        for (path, idx) in added {
+            let container = match path.extension().and_then(|e| e.to_str()) {
+                Some(ext) => match Container::try_from(ext) {
+                    Ok(container) => container,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+
+            let library = &mut self.libraries[idx];
+            let file = match path.strip_prefix(library.location()) {
+                Ok(file) => file.to_path_buf(),
+                Err(_) => continue,
+            };
+
+            info!("Library {}: {} added", library.name(), file.display());
+            library.insert(path.clone(), container);
+            let indexed = match library.files().get(&path) {
+                Some(indexed) => indexed,
+                None => continue,
+            };
+
+            // What sync would write to each device, once for all those
+            // wanting the same
+            let mut profiles: Vec<Profile> = Vec::new();
+            let wanted =
+                |d: &&Device| d.library_root(library.name()).is_some() && !d.is_excluded(&file);
+            for dev in devices.iter().filter(wanted) {
+                match crate::action(dev, indexed, &[]) {
+                    Ok(Action::Transcode(profile)) if !profiles.contains(&profile) => {
+                        profiles.push(profile)
+                    }
+                    Ok(_) => (),
+                    Err(err) => warn!("Could not pre-transcode {}: {}", path.display(), err),
+                }
+            }
+
+            for profile in profiles {
+                match self.cache.get_or_insert(&path, &profile, &mut |_| ()) {
+                    Ok(cached) => cached_now.push(cached),
+                    Err(err) => warn!("Could not pre-transcode {}: {}", path.display(), err),
+                }
+            }
+        }
+
+        // The transcodes just made are for the next sync, keep them
+        if !cached_now.is_empty() {
+            self.cache.prune(&cached_now)?;
+        }
+        Ok(())
+    }
+}
+
+fn run_hook(conf: &Config, event: HookEvent, device: &str, err: Option<&str>) {
+    let hooks = conf.get_hooks();
+    let cmd = match event {
+        HookEvent::Start => &hooks.on_start,
+        HookEvent::Finish => &hooks.on_finish,
+        HookEvent::Failure => &hooks.on_failure,
+    };
+
+    if let Some(cmd) = cmd {
+        let event: &'static str = event.into();
+        debug!("Running {} hook: {}", event, cmd);
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("MUCO_EVENT", event)
+            .env("MUCO_DEVICE", device)
+            .env("MUCO_ERROR", err.unwrap_or_default())
+            .status()
+            .context(Kind::Hook);
+
+        match status {
+            Ok(status) if !status.success() => error!("Hook \"{}\" exited with {}", cmd, status),
+            Err(err) => error!("Hook \"{}\" could not be run: {}", cmd, err),
+            _ => (),
+        }
+    }
+}
+
+/// Parses the mount points out of a file in the format of `/proc/self/mountinfo`
+pub fn mount_points(mountinfo: &Path) -> Result<Vec<PathBuf>> {
+    let content = std::fs::read_to_string(mountinfo).context(Kind::MountTable)?;
+    Ok(parse_mount_points(&content))
+}
+
+fn parse_mount_points(content: &str) -> Vec<PathBuf> {
+    content
+        .lines()
+        // The fifth field is the mount point relative to the process root
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(|point| PathBuf::from(unescape(point)))
+        .collect()
+}
+
+// The kernel escapes space, tab, newline and backslash as octal sequences
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let seq: String = chars.clone().take(3).collect();
+            if let Ok(byte) = u8::from_str_radix(&seq, 8) {
+                out.push(byte as char);
+                chars.nth(2);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+// A device is mounted when the deepest mount point holding its location is
+// the location itself, or holds it as a directory, the location being a
+// folder of the mounted filesystem. `/` holds every location, and mount
+// points of the base system like `/run` or `/home` hold locations which do
+// not exist until the device gets mounted.
+fn is_mounted(location: &Path, mount_points: &[PathBuf]) -> bool {
+    mount_points
+        .iter()
+        .filter(|point| location.starts_with(point))
+        .max_by_key(|point| point.components().count())
+        .map(|point| point.parent().is_some() && (point == location || location.is_dir()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+24 22 0:21 / /run rw,nosuid,nodev shared:5 - tmpfs tmpfs rw
+25 22 8:3 / /home rw,relatime shared:6 - ext4 /dev/sda3 rw
+26 22 8:4 / /media rw,relatime shared:7 - ext4 /dev/sda4 rw
+27 22 0:45 / /mnt/nas rw,relatime shared:8 - nfs4 nas:/music rw
+";
+
+    fn mounted(extra: &str, location: &str) -> bool {
+        let points = parse_mount_points(&format!("{}{}", MOUNTINFO, extra));
+        is_mounted(Path::new(location), &points)
+    }
+
+    #[test]
+    fn mount_points_are_unescaped() {
+        let points =
+            parse_mount_points("30 22 8:17 / /run/media/aj/MY\\040STICK rw - vfat /dev/sdb1 rw\n");
+        assert_eq!(points, vec![PathBuf::from("/run/media/aj/MY STICK")]);
+    }
+
+    #[test]
+    fn devices_are_mounted_at_their_location() {
+        let stick = "30 24 8:17 / /run/media/aj/STICK rw - vfat /dev/sdb1 rw\n";
+        let nested = "31 27 8:33 / /mnt/nas/car rw - vfat /dev/sdc1 rw\n";
+        let cases = [
+            // Mounts of the base system hold every location below them
+            ("", "/run/media/aj/STICK", false),
+            ("", "/home/aj/stick", false),
+            ("", "/media/stick", false),
+            ("", "/mnt/nas/car", false),
+            ("", "/mnt/stick", false),
+            (stick, "/run/media/aj/STICK", true),
+            (stick, "/run/media/aj/OTHER", false),
+            (stick, "/run/media/aj", false),
+            (nested, "/mnt/nas/car", true),
+            (nested, "/mnt/nas", true),
+            (
+                "32 26 8:49 / /media/stick rw - vfat /dev/sdd1 rw\n",
+                "/media/stick",
+                true,
+            ),
+            (
+                "33 25 8:65 / /home/aj/stick rw - vfat /dev/sde1 rw\n",
+                "/home/aj/stick",
+                true,
+            ),
+        ];
+
+        for (extra, location, expected) in cases.iter() {
+            assert_eq!(mounted(extra, location), *expected, "{}", location);
+        }
+    }
+
+    #[test]
+    fn devices_are_mounted_below_their_location() {
+        let root = std::env::temp_dir().join(format!("muco-mounted-{}", std::process::id()));
+        let stick = root.join("stick");
+        let music = stick.join("Music");
+        let mount = format!("30 22 8:17 / {} rw - vfat /dev/sdb1 rw\n", stick.display());
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&stick).unwrap();
+
+        let music = music.to_str().unwrap();
+        assert!(!mounted(&mount, music));
+        std::fs::create_dir(music).unwrap();
+        assert!(mounted(&mount, music));
+        // Only mounted when the filesystem is
+        assert!(!mounted("", music));
+        // The root filesystem holds everything
+        assert!(!is_mounted(Path::new(music), &[PathBuf::from("/")]));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn poll_reports_devices_plugged_in() {
+        let mountinfo = std::env::temp_dir().join(format!("muco-mountinfo-{}", std::process::id()));
+        let conf: Config = toml::from_str(
+            r#"
+            libraries = []
+            [[devices]]
+            name = "Car"
+            location = "/run/media/aj/CAR"
+            format = "Mp3"
+            "#,
+        )
+        .unwrap();
+        let stick = "30 24 8:17 / /run/media/aj/CAR rw - vfat /dev/sdb1 rw\n";
+        let mut watcher = Watcher::new(mountinfo.clone(), Duration::from_secs(1));
+
+        let mut poll = |extra: &str| {
+            std::fs::write(&mountinfo, format!("{}{}", MOUNTINFO, extra)).unwrap();
+            watcher.poll(&conf).unwrap()
+        };
+        assert!(poll("").is_empty());
+        assert_eq!(poll(stick), vec!["Car".to_owned()]);
+        assert!(poll(stick).is_empty());
+        assert!(poll("").is_empty());
+        assert_eq!(poll(stick), vec!["Car".to_owned()]);
+
+        std::fs::remove_file(&mountinfo).unwrap();
+    }
+}